@@ -1,68 +1,1026 @@
+use std::ffi::OsString;
 use std::fs;
-use std::path::Path;
-use anyhow::{Context, Result};
-use chrono::{Duration, Local};
-use tracing::info;
-use crate::syncer_util::{count_timestamp_named_folders, latest_timestamp_named_dir, rsync_apply_diff, rsync_extract_diff, RsyncDirection};
-use crate::util::{CpMvMode, fs_copy, fs_move};
-
-pub fn archive_local(working_dir: &Path, local_archive: &Path, exclude_file: &Path, date_format: &str) -> Result<()> {
-    let latest_archived_timestamp = latest_timestamp_named_dir(local_archive, date_format)?;
-    info!("Latest archived: {:?}", latest_archived_timestamp);
-
-    let (latest_archived_path, mut is_fast_forward) = match latest_archived_timestamp {
-        Some(latest_datetime) => {
-            let is_today = latest_datetime.date_naive() == Local::now().date_naive();
-            let path = local_archive.join(latest_datetime.format(date_format).to_string());
-            (path, is_today)
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context, Result};
+use std::time::SystemTime;
+use chrono::{DateTime, Duration, FixedOffset, Local};
+use tracing::{error, info, warn};
+use crate::backend::{Backend, FsBackend};
+use crate::backpressure::check_backpressure;
+use crate::config::{Config, SnapshotStrategy, SpecialFiles};
+use crate::events::{ArchiveEvent, EventSink, LoggingSink};
+use crate::git::check_working_dir;
+use crate::lock::ArchiveLock;
+use crate::manifest::{find_special_files, snapshot_merkle_root, ExcludeMatcher};
+use crate::meta::{read_meta, SnapshotMeta};
+use crate::metrics::write_metrics;
+use crate::mirror::mirror_snapshots;
+use crate::retention::{retention_plan, RetentionPolicy};
+use crate::state::{read_state, write_state};
+use crate::syncer_util::{ChangeList, latest_snapshot_dir, rsync_extract_diff, rsync_transfer, find_diff_file, sidecar_path, RsyncDirection, RsyncStats, CHANGES_EXT, FILELIST_EXT, META_EXT, RSYNCLOG_EXT};
+use crate::util::{concat_str_os, create_temp_dir, is_empty_dir, max_mtime, with_heartbeat};
+
+/// Logs the FIFOs, sockets and device nodes in the working dir, fails if `special_files = "error"`.
+fn check_special_files(config: &Config) -> Result<()> {
+    let excludes = ExcludeMatcher::load(&config.exclude)?;
+    let special = find_special_files(config.source_dir(), &excludes)?;
+    if special.is_empty() {
+        return Ok(());
+    }
+    match config.special_files {
+        SpecialFiles::Preserve => warn!("archiving {} special files as they are: {special:?}", special.len()),
+        SpecialFiles::Skip => info!("skipping {} special files: {special:?}", special.len()),
+        SpecialFiles::Error => return Err(anyhow!("{} special files in the working dir (special_files = \"error\"), exclude them: {special:?}", special.len())),
+    }
+    Ok(())
+}
+
+/// Fails if `changes` exceed `max_changed_files` or `max_change_ratio` of the files in snapshot `latest`.
+fn check_change_limits(backend: &dyn Backend, config: &Config, latest: &str, changes: &ChangeList) -> Result<()> {
+    // created files are in `changed` too
+    let changed = changes.changed.len() + changes.deleted.len() + changes.moved.len();
+    if let Some(max) = config.max_changed_files {
+        if changed > max {
+            return Err(anyhow!("{changed} files changed, deleted or moved, more than max_changed_files = {max}, not archiving (use --force to archive anyway)"));
+        }
+    }
+    if let Some(max_ratio) = config.max_change_ratio {
+        match backend.file_count(latest)? {
+            Some(0) => {}
+            Some(count) if changed as f64 / count as f64 > max_ratio => {
+                return Err(anyhow!("{changed} files changed, deleted or moved, {latest} has {count}, more than max_change_ratio = {max_ratio}, not archiving (use --force to archive anyway)"));
+            }
+            Some(_) => {}
+            None => warn!("can't count the files of {latest}, max_change_ratio is not checked"),
+        }
+    }
+    Ok(())
+}
+
+/// Fails if the working dir is empty or missing, e.g. an unmounted drive, while snapshot `latest` is known not to
+/// be, unless `archive_empty` is set.
+fn check_empty_source(backend: &dyn Backend, config: &Config, latest: &str) -> Result<()> {
+    let source = config.source_dir();
+    if config.archive_empty || !is_empty_dir(source).context(format!("reading {source:?}"))? {
+        return Ok(());
+    }
+    // None: the backend can't tell, so don't block the run
+    if backend.file_count(latest)?.is_none_or(|count| count == 0) {
+        return Ok(());
+    }
+    Err(anyhow!("{source:?} is empty or missing, but {latest} isn't, not archiving (use --archive-empty --force to snapshot an empty working dir)"))
+}
+
+/// True for the empty snapshot an archive starts with: every real snapshot gets a change list and a `.meta.json`.
+fn is_empty_base(backend: &dyn Backend, name: &str) -> Result<bool> {
+    Ok(!backend.has_sidecar(name, CHANGES_EXT)? && !backend.has_sidecar(name, META_EXT)?)
+}
+
+/// Returns true if nothing in the working dir was modified after the latest snapshot was taken.
+/// Any doubt (no change list, unreadable files) means false and a real rsync diff.
+fn nothing_modified_since(backend: &dyn Backend, working_dir: &Path, snapshot_name: &str, taken_at: DateTime<FixedOffset>) -> Result<bool> {
+    if !backend.has_sidecar(snapshot_name, CHANGES_EXT)? {
+        // initial empty snapshot or a crashed run
+        return Ok(false);
+    }
+    match max_mtime(working_dir) {
+        Ok(latest_mtime) => Ok(latest_mtime < SystemTime::from(taken_at)),
+        Err(e) => {
+            warn!("quick skip check failed, running rsync: {e}");
+            Ok(false)
+        }
+    }
+}
+
+/// What an archive run did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveOutcome {
+    Created,
+    NoChanges,
+    /// `quick_skip` found nothing modified, rsync wasn't run
+    Skipped,
+    /// The latest snapshot is younger than `min_interval`
+    TooSoon,
+    /// A `[backpressure]` limit was exceeded, rsync wasn't run
+    Deferred,
+}
+
+#[derive(Debug)]
+pub struct ArchiveReport {
+    pub outcome: ArchiveOutcome,
+    /// Name of the created snapshot
+    pub snapshot: Option<String>,
+    /// Folder of the created snapshot, for local archives
+    pub snapshot_path: Option<PathBuf>,
+    pub changes: Option<ChangeList>,
+    pub started: DateTime<Local>,
+    pub finished: DateTime<Local>,
+}
+
+impl ArchiveReport {
+    fn new(outcome: ArchiveOutcome, started: DateTime<Local>) -> Self {
+        ArchiveReport { outcome, snapshot: None, snapshot_path: None, changes: None, started, finished: Local::now() }
+    }
+}
+
+impl Display for ArchiveReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let seconds = (self.finished - self.started).num_milliseconds() as f64 / 1000.0;
+        match (self.outcome, &self.changes) {
+            (ArchiveOutcome::Created, Some(changes)) => {
+                let location = match &self.snapshot_path {
+                    Some(path) => path.display().to_string(),
+                    None => self.snapshot.clone().unwrap_or_default(),
+                };
+                write!(f, "created {location}: {} changed, {} moved, {} deleted in {seconds:.1}s",
+                       changes.changed.len(), changes.moved.len(), changes.deleted.len())
+            }
+            (ArchiveOutcome::Skipped, _) => write!(f, "nothing modified, skipped in {seconds:.1}s"),
+            (ArchiveOutcome::TooSoon, _) => write!(f, "latest snapshot is younger than min_interval, skipped"),
+            (ArchiveOutcome::Deferred, _) => write!(f, "system under pressure, skipped"),
+            _ => write!(f, "no changes, checked in {seconds:.1}s"),
+        }
+    }
+}
+
+pub fn archive_local(config: &Config) -> Result<ArchiveReport> {
+    archive_with_events(config, &LoggingSink)
+}
+
+/// Archives `config` into its local archive or `[s3]` under the archive lock, then copies new snapshots to the
+/// `[mirror]`. This is what `archive` and the daemon run per target.
+pub fn archive_locked(config: &Config) -> Result<ArchiveReport> {
+    let _lock = ArchiveLock::acquire(&config.local_archive)?;
+    let report = match &config.s3 {
+        #[cfg(feature = "s3")]
+        Some(s3) => archive(&crate::s3::S3Backend::new(config, s3)?, config, &LoggingSink)?,
+        #[cfg(not(feature = "s3"))]
+        Some(_) => return Err(anyhow!("[s3] is configured, but built without the s3 feature")),
+        None => archive_local(config)?
+    };
+    if let (Some(mirror), None) = (&config.mirror, &config.s3) {
+        // the snapshot is safe locally, a failing mirror is reported and retried on the next run
+        match mirror_snapshots(config, mirror) {
+            Ok(count) => info!("mirrored {count} snapshots"),
+            Err(e) => error!("mirroring failed: {e:#}"),
+        }
+    }
+    Ok(report)
+}
+
+/// Archives into `local_archive` like `archive_local`, reporting each step to `sink`.
+pub fn archive_with_events(config: &Config, sink: &dyn EventSink) -> Result<ArchiveReport> {
+    let heartbeat = config.heartbeat_secs.map(std::time::Duration::from_secs);
+    let result = with_heartbeat("archive", heartbeat, || archive_local_inner(config, sink));
+    if let Some(metrics_file) = &config.metrics_file {
+        if let Err(e) = write_metrics(metrics_file, config, result.as_ref().ok()) {
+            warn!("unable to write metrics: {e:#}");
+        }
+    }
+    result
+}
+
+fn archive_local_inner(config: &Config, sink: &dyn EventSink) -> Result<ArchiveReport> {
+    if let Some(reason) = check_backpressure(&config.backpressure, &config.local_archive)? {
+        warn!("not archiving now: {reason}");
+        sink.event(&ArchiveEvent::Started);
+        let report = ArchiveReport::new(ArchiveOutcome::Deferred, Local::now());
+        sink.event(&ArchiveEvent::Finished { outcome: report.outcome, snapshot: None });
+        return Ok(report);
+    }
+    let working_dir = &config.local_working_dir;
+    let source_mtime = match config.since_last_success {
+        true => Some(max_mtime(config.source_dir()).context(format!("reading mtimes in {working_dir:?}"))?),
+        false => None
+    };
+    if let (Some(mtime), Some(state)) = (source_mtime, read_state(&config.local_archive, working_dir)) {
+        if mtime <= state.source_max_mtime {
+            info!("nothing modified since the last successful run at {}, skipping", state.last_success);
+            sink.event(&ArchiveEvent::Started);
+            let report = ArchiveReport::new(ArchiveOutcome::NoChanges, Local::now());
+            sink.event(&ArchiveEvent::Finished { outcome: report.outcome, snapshot: None });
+            return Ok(report);
+        }
+    }
+    let mut report = archive(&FsBackend::new(config), config, sink)?;
+    report.snapshot_path = report.snapshot.as_ref().map(|name| config.local_archive.join(name));
+    if let Some(link) = &config.latest_link {
+        update_latest_link(config, link)?;
+    }
+    if let Some(mtime) = source_mtime {
+        if matches!(report.outcome, ArchiveOutcome::Created | ArchiveOutcome::NoChanges | ArchiveOutcome::Skipped) {
+            write_state(&config.local_archive, working_dir, mtime)?;
+        }
+    }
+    Ok(report)
+}
+
+/// Points `local_archive/<link>` at the newest snapshot, a file or folder with that name is left alone.
+fn update_latest_link(config: &Config, link: &str) -> Result<()> {
+    let naming = config.naming();
+    let latest = match latest_snapshot_dir(&config.local_archive, &naming)? {
+        Some((_, latest)) => latest,
+        None => return Ok(())
+    };
+    let link_path = config.local_archive.join(link);
+    match fs::symlink_metadata(&link_path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            fs::remove_file(&link_path).context(format!("removing {link_path:?}"))?;
+        }
+        Ok(_) => {
+            warn!("{link_path:?} is not a symlink, not updating it");
+            return Ok(());
+        }
+        Err(_) => {}
+    }
+    // relative, so that the archive can be moved
+    std::os::unix::fs::symlink(&latest, &link_path).context(format!("creating {link_path:?}"))
+}
+
+/// A snapshot whose batch file and change list were extracted, but which wasn't created yet.
+/// `<name>.diff` applies to snapshot `base`, which must stay the latest one until the snapshot is finished.
+pub struct PendingSnapshot {
+    pub base: String,
+    pub name: String,
+    pub changes: ChangeList,
+    /// Commit checked out in the working dir, see `[git] record_head`
+    pub git_head: Option<String>,
+}
+
+pub fn archive(backend: &dyn Backend, config: &Config, sink: &dyn EventSink) -> Result<ArchiveReport> {
+    let started = Local::now();
+    sink.event(&ArchiveEvent::Started);
+    let report = match extract(backend, config, sink)? {
+        Extraction::Pending(pending) => {
+            finish(backend, config, &pending, started, sink)?;
+            ArchiveReport {
+                snapshot: Some(pending.name),
+                changes: Some(pending.changes),
+                ..ArchiveReport::new(ArchiveOutcome::Created, started)
+            }
         }
+        Extraction::NoChanges => ArchiveReport::new(ArchiveOutcome::NoChanges, started),
+        Extraction::Skipped => ArchiveReport::new(ArchiveOutcome::Skipped, started),
+        Extraction::TooSoon => ArchiveReport::new(ArchiveOutcome::TooSoon, started),
+    };
+    sink.event(&ArchiveEvent::Finished { outcome: report.outcome, snapshot: report.snapshot.as_deref() });
+    Ok(report)
+}
+
+pub enum Extraction {
+    Pending(PendingSnapshot),
+    NoChanges,
+    Skipped,
+    TooSoon,
+}
+
+/// First half of an archive run: diffs the working dir against the latest snapshot and writes the batch file.
+/// Creates the empty base snapshot in an empty archive.
+pub fn extract(backend: &dyn Backend, config: &Config, sink: &dyn EventSink) -> Result<Extraction> {
+    let working_dir = config.source_dir();
+    let naming = &config.naming();
+    let git_head = check_working_dir(&config.git, working_dir)?;
+
+    let latest_archived_snapshot = backend.latest_snapshot()?;
+    info!("Latest archived: {:?}", latest_archived_snapshot);
+
+    let latest_archived = match latest_archived_snapshot {
+        Some((latest_datetime, name)) => {
+            if let Some(min_interval) = config.min_interval()? {
+                if Local::now().fixed_offset() - latest_datetime < min_interval {
+                    info!("{name} is younger than min_interval, skipping");
+                    return Ok(Extraction::TooSoon);
+                }
+            }
+            if let Some(base) = &config.base_snapshot {
+                if !backend.has_snapshot(base)? {
+                    return Err(anyhow!("there is no snapshot {base:?} to use as the base"));
+                }
+                info!("diffing against {base} instead of the latest snapshot {name}");
+                base.clone()
+            } else if config.quick_skip && nothing_modified_since(backend, working_dir, &name, latest_datetime)? {
+                info!("nothing modified since {name}, skipping");
+                return Ok(Extraction::Skipped);
+            } else {
+                name
+            }
+        }
+        None if config.base_snapshot.is_some() => return Err(anyhow!("--base given, but there are no snapshots yet")),
         None => {
-            let now = (Local::now() - Duration::seconds(1)).format(date_format).to_string();
-            let path = local_archive.join(now);
+            let now = naming.format(&(Local::now() - Duration::seconds(1)));
             info!("empty archive folder, create first empty folder");
-            fs::create_dir(path.clone())?;
-            (path, false)
+            backend.create_empty_snapshot(&now)?;
+            now
         }
     };
-    // do not fast forward if only one archived folder exists, otherwise it will be lost
-    is_fast_forward = if count_timestamp_named_folders(local_archive, date_format)? == 1 {
-        false
-    } else {
-        is_fast_forward
+
+    check_special_files(config)?;
+    check_empty_source(backend, config, &latest_archived)?;
+    let now = naming.format(&Local::now());
+    match backend.extract_changes(&latest_archived, &now)? {
+        Some(mut changed) => {
+            // rsync output order varies between runs, sorted change lists of equal runs are equal
+            changed.sort();
+            if let Err(e) = check_change_limits(backend, config, &latest_archived, &changed) {
+                backend.discard_changes(&now)?;
+                return Err(e);
+            }
+            sink.event(&ArchiveEvent::DetectedDiff { snapshot: &now, changes: &changed });
+            Ok(Extraction::Pending(PendingSnapshot { base: latest_archived, name: now, changes: changed, git_head }))
+        }
+        None => {
+            info!("no changes");
+            Ok(Extraction::NoChanges)
+        }
+    }
+}
+
+/// Whether a new snapshot should replace the latest one instead of being added next to it: only when the latest
+/// was taken today, and never when it is the only snapshot, as the archive would lose its base then.
+pub fn should_fast_forward(latest: DateTime<FixedOffset>, snapshot_count: usize) -> bool {
+    snapshot_count > 1 && latest.date_naive() == Local::now().date_naive()
+}
+
+/// Second half of an archive run: creates snapshot `pending.name` from its base and batch file and writes the
+/// remaining sidecars. The snapshot is published last, it is complete then.
+pub fn finish(backend: &dyn Backend, config: &Config, pending: &PendingSnapshot, started: DateTime<Local>, sink: &dyn EventSink) -> Result<()> {
+    let naming = &config.naming();
+    let PendingSnapshot { base: latest_archived, name: now, changes: changed, git_head } = pending;
+
+    let snapshot_count = backend.snapshot_count()?;
+    // an older base is kept, it is part of the history
+    // an intentionally empty snapshot never replaces the one before it
+    let is_fast_forward = config.fast_forward
+        && config.base_snapshot.is_none()
+        && !config.archive_empty
+        && naming.parse(latest_archived).is_some_and(|latest| should_fast_forward(latest, snapshot_count))
+        && backend.can_rename_snapshot(latest_archived);
+
+    if !is_fast_forward {
+        backend.check_free_space(latest_archived)?;
+    }
+    backend.copy_snapshot(latest_archived, now, is_fast_forward)?;
+    sink.event(&ArchiveEvent::CopiedBase { snapshot: now, base: latest_archived, fast_forward: is_fast_forward });
+    backend.apply_changes(latest_archived, now, is_fast_forward)?;
+    sink.event(&ArchiveEvent::AppliedDiff { snapshot: now });
+    if config.keep_rsync_log {
+        backend.write_sidecar(now, RSYNCLOG_EXT, backend.command_output().as_bytes()).context("writing rsync log")?;
+    }
+
+    info!("saving change list");
+    write_change_list(backend, now, changed)?;
+    if config.write_file_list {
+        backend.write_sidecar(now, FILELIST_EXT, changed.to_file_list().as_bytes()).context("writing file list")?;
+    }
+
+    let mut meta = SnapshotMeta::new(config, started, backend.commands(), latest_archived, git_head.clone())?;
+    if is_empty_base(backend, latest_archived)? {
+        meta.baseline_file_count = Some(changed.changed.len());
+    }
+    if config.merkle_root {
+        info!("hashing snapshot");
+        meta.merkle_root = backend.merkle_root(now)?.map(|root| root.to_hex().to_string());
+    }
+    let meta_json = serde_json::to_string_pretty(&meta).context("serializing snapshot meta")?;
+    backend.write_sidecar(now, META_EXT, meta_json.as_bytes()).context("writing snapshot meta")?;
+    backend.publish_snapshot(now)?;
+    sink.event(&ArchiveEvent::WroteChanges { snapshot: now });
+    Ok(())
+}
+
+pub fn write_change_list(backend: &dyn Backend, name: &str, changes: &ChangeList) -> Result<()> {
+    let changed_json = serde_json::to_string(changes).context("serializing change list")?;
+    backend.write_sidecar(name, CHANGES_EXT, changed_json.as_bytes()).context("writing change list")
+}
+
+/// Runs only `extract` and saves the change list, so that `apply_local` can finish the snapshot later.
+/// Returns the name of the pending snapshot.
+pub fn extract_local(config: &Config) -> Result<Option<String>> {
+    let backend = FsBackend::new(config);
+    let pending = match extract(&backend, config, &LoggingSink)? {
+        Extraction::Pending(pending) => pending,
+        Extraction::NoChanges | Extraction::Skipped | Extraction::TooSoon => return Ok(None)
+    };
+    write_change_list(&backend, &pending.name, &pending.changes)?;
+    Ok(Some(pending.name))
+}
+
+/// Finishes snapshot `name` left pending by `extract_local`, its base is the latest snapshot.
+pub fn apply_local(config: &Config, name: &str) -> Result<()> {
+    if config.snapshot_strategy != SnapshotStrategy::CopyApply {
+        return Err(anyhow!("apply needs snapshot_strategy = \"copy-apply\", other strategies read the working dir"));
+    }
+    let started = Local::now();
+    let naming = config.naming();
+    let timestamp = naming.parse(name).ok_or(anyhow!("{name:?} is not a snapshot name"))?;
+    if config.local_archive.join(name).exists() {
+        return Err(anyhow!("snapshot {name} already exists"));
+    }
+    if find_diff_file(&config.local_archive, name).is_none() {
+        return Err(anyhow!("there is no batch file for {name}, run extract first"));
+    }
+    let changes_path = sidecar_path(&config.local_archive, name, CHANGES_EXT);
+    let changes = fs::read_to_string(&changes_path).context(format!("reading {changes_path:?}"))?;
+    let changes: ChangeList = serde_json::from_str(&changes).context(format!("parsing {changes_path:?}"))?;
+    let (latest, latest_name) = latest_snapshot_dir(&config.local_archive, &naming)?
+        .ok_or(anyhow!("there are no snapshots in {:?}", config.local_archive))?;
+    if latest >= timestamp {
+        return Err(anyhow!("{latest_name} is newer than {name}, the batch file no longer applies to the latest snapshot"));
+    }
+    // the working dir may have changed since extract, its commit isn't recorded
+    let pending = PendingSnapshot { base: latest_name, name: name.to_owned(), changes, git_head: None };
+    finish(&FsBackend::new(config), config, &pending, started, &LoggingSink)?;
+    if let Some(link) = &config.latest_link {
+        update_latest_link(config, link)?;
+    }
+    Ok(())
+}
+
+/// Name of the latest snapshot if its recorded Merkle root matches the one of the working dir, a quick equality
+/// check without rsync. None if they differ or the snapshot has no root, e.g. without `merkle_root = true`.
+pub fn latest_matching_merkle_root(config: &Config) -> Result<Option<String>> {
+    let (_, latest) = latest_snapshot_dir(&config.local_archive, &config.naming())?
+        .ok_or(anyhow!("there are no snapshots in {:?}", config.local_archive))?;
+    let recorded = match read_meta(&config.local_archive, &latest)?.and_then(|meta| meta.merkle_root) {
+        Some(root) => root,
+        None => return Ok(None)
+    };
+    let excludes = ExcludeMatcher::load(&config.exclude)?;
+    let live = snapshot_merkle_root(config.source_dir(), &excludes)?;
+    Ok((live.to_hex().as_str() == recorded).then_some(latest))
+}
+
+/// Whether snapshot `name` still has the Merkle root recorded in its `.meta.json`, None if none was recorded.
+pub fn verify_merkle_root(config: &Config, name: &str) -> Result<Option<bool>> {
+    let recorded = match read_meta(&config.local_archive, name)?.and_then(|meta| meta.merkle_root) {
+        Some(root) => root,
+        None => return Ok(None)
     };
+    let excludes = ExcludeMatcher::load(&config.exclude)?;
+    let actual = snapshot_merkle_root(&config.local_archive.join(name), &excludes)?;
+    Ok(Some(actual.to_hex().as_str() == recorded))
+}
+
+/// Checks the Merkle roots of the snapshots `policy` keeps, fails if one doesn't match. Snapshots without a
+/// recorded root are skipped with a warning.
+pub fn verify_retained(config: &Config, policy: &RetentionPolicy) -> Result<()> {
+    let kept = retention_plan(&config.local_archive, &config.naming(), policy)?.into_iter()
+        .filter(|(_, reasons)| !reasons.is_empty());
+    let mut corrupt = Vec::new();
+    for (name, _) in kept {
+        match verify_merkle_root(config, &name)? {
+            Some(true) => info!("{name} is intact"),
+            Some(false) => {
+                error!("kept snapshot {name} doesn't match its recorded Merkle root");
+                corrupt.push(name);
+            }
+            None => warn!("{name} has no recorded Merkle root, not verified"),
+        }
+    }
+    if !corrupt.is_empty() {
+        return Err(anyhow!("{} kept snapshots are corrupt, not pruning: {corrupt:?}", corrupt.len()));
+    }
+    Ok(())
+}
 
+/// Diffs the working dir against the latest snapshot without creating anything, moves are resolved.
+pub fn diff_against_latest(config: &Config) -> Result<Option<ChangeList>> {
+    let naming = config.naming();
+    let (_, latest) = latest_snapshot_dir(&config.local_archive, &naming)?
+        .ok_or(anyhow!("there are no snapshots in {:?}", config.local_archive))?;
+    let latest_archived_path = config.local_archive.join(latest);
+    let temp_dir = create_temp_dir(config.temp_dir.as_deref())?;
     let rsync_dir = RsyncDirection::LocalToLocal {
-        from: working_dir.to_path_buf(),
+        from: config.source_dir().to_path_buf(),
         to: latest_archived_path.clone()
     };
-    let now = Local::now().format(date_format).to_string();
-    let diff_filename = now.clone() + ".diff";
-    let diff_filepath = local_archive.join(diff_filename);
-    let diff = rsync_extract_diff(rsync_dir, &diff_filepath, exclude_file)?;
-    match diff {
-        Some(mut changed) => {
-            info!("changed raw: {changed:?}");
-            changed.extract_moves(&latest_archived_path, working_dir);
-            info!("try find moved files: {changed:?}");
-            if is_fast_forward {
-                info!("fast-forwarding by renaming latest archived folder");
-                fs_move(&latest_archived_path, local_archive, CpMvMode::FolderRename(now.clone()))?;
-            } else {
-                info!("copying latest archived folder");
-                fs_copy(&latest_archived_path, local_archive, CpMvMode::FolderRename(now.clone()))?;
-            }
+    let diff = rsync_extract_diff(rsync_dir, Some(&temp_dir.path().join("diff")), &config.exclude, &config.rsync_options())?;
+    Ok(diff.map(|mut changed| {
+        changed.extract_moves(&latest_archived_path, config.source_dir(), &config.move_detection());
+        changed
+    }))
+}
 
-            let new_latest_archived = local_archive.join(now.clone());
-            info!("applying diff file");
-            rsync_apply_diff(&new_latest_archived, &diff_filepath, exclude_file)?;
+/// Statistics of a dry run from the working dir into the latest snapshot: how many files and bytes the next archive
+/// run would transfer, nothing is created.
+pub fn estimate_transfer(config: &Config) -> Result<RsyncStats> {
+    let (_, latest) = latest_snapshot_dir(&config.local_archive, &config.naming())?
+        .ok_or(anyhow!("there are no snapshots in {:?}", config.local_archive))?;
+    let options = config.rsync_options();
+    let mut extra_args = vec![OsString::from("--dry-run"), OsString::from("--stats"), OsString::from("--delete")];
+    extra_args.extend(options.files_from.iter().map(|list| concat_str_os("--files-from=", list)));
+    extra_args.extend(options.to_args());
+    let output = rsync_transfer(RsyncDirection::LocalToLocal {
+        from: config.source_dir().to_path_buf(),
+        to: config.local_archive.join(latest)
+    }, &config.exclude, &extra_args, &options)?;
+    RsyncStats::parse(&output).ok_or(anyhow!("rsync printed no statistics"))
+}
 
-            info!("saving change list");
-            let changed_json = serde_json::to_string(&changed).context("serializing change list")?;
-            fs::write(local_archive.join(format!("{}.changes", now)), changed_json).context("writing change list")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syncer_util::{count_timestamp_named_folders, staging_name, FsEntity, DIFF_EXT};
+    use std::cell::{Cell, RefCell};
+    use crate::test_support::{naming, rsync_available, snapshot_name, test_config};
+    use crate::util::LazyTempDir;
+
+    fn changes(changed: usize, deleted: usize, moved: usize) -> ChangeList {
+        let file = |i| FsEntity::file(&format!("file{i}"));
+        ChangeList {
+            changed: (0..changed).map(file).collect(),
+            deleted: (0..deleted).map(file).collect(),
+            moved: (0..moved).map(|i| (file(i), format!("moved/file{i}").into())).collect(),
+            created: Vec::new(),
         }
-        None => {
-            info!("no changes")
+    }
+
+    #[test]
+    fn change_limits_count_deleted_and_moved_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.max_changed_files = Some(3);
+        let backend = FsBackend::new(&config);
+        assert!(check_change_limits(&backend, &config, "latest", &changes(1, 1, 1)).is_ok());
+        assert!(check_change_limits(&backend, &config, "latest", &changes(3, 0, 0)).is_ok());
+        assert!(check_change_limits(&backend, &config, "latest", &changes(0, 4, 0)).is_err());
+        assert!(check_change_limits(&backend, &config, "latest", &changes(1, 1, 2)).is_err());
+    }
+
+    #[test]
+    fn change_ratio_is_relative_to_the_latest_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.max_change_ratio = Some(0.5);
+        let latest = config.local_archive.join("latest");
+        fs::create_dir(&latest).unwrap();
+        for i in 0..4 {
+            fs::write(latest.join(format!("file{i}")), "").unwrap();
         }
+        let backend = FsBackend::new(&config);
+        assert!(check_change_limits(&backend, &config, "latest", &changes(1, 1, 0)).is_ok());
+        assert!(check_change_limits(&backend, &config, "latest", &changes(1, 1, 1)).is_err());
+        assert!(check_change_limits(&backend, &config, "latest", &changes(0, 0, 3)).is_err());
     }
-    Ok(())
-}
\ No newline at end of file
+
+    #[test]
+    fn empty_source_is_archived_onto_an_empty_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        fs::create_dir(config.local_archive.join("latest")).unwrap();
+        let backend = FsBackend::new(&config);
+        assert!(check_empty_source(&backend, &config, "latest").is_ok());
+    }
+
+    #[test]
+    fn empty_source_is_refused_when_the_latest_snapshot_has_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        let latest = config.local_archive.join("latest");
+        fs::create_dir(&latest).unwrap();
+        fs::write(latest.join("file.txt"), "contents").unwrap();
+        let backend = FsBackend::new(&config);
+        assert!(check_empty_source(&backend, &config, "latest").is_err());
+
+        // a missing working dir looks the same, e.g. an unmounted drive
+        fs::remove_dir(&config.local_working_dir).unwrap();
+        assert!(check_empty_source(&backend, &config, "latest").is_err());
+
+        config.archive_empty = true;
+        let backend = FsBackend::new(&config);
+        assert!(check_empty_source(&backend, &config, "latest").is_ok());
+    }
+
+    /// Backend keeping snapshot names and sidecars in memory, `extract_changes` returns `changes`. Every snapshot
+    /// holds one file.
+    struct MemoryBackend {
+        snapshots: RefCell<Vec<String>>,
+        sidecars: RefCell<Vec<(String, String)>>,
+        changes: Option<ChangeList>,
+        /// Whether the last `copy_snapshot` fast-forwarded
+        fast_forwarded: Cell<Option<bool>>,
+    }
+
+    impl Backend for MemoryBackend {
+        fn latest_snapshot(&self) -> Result<Option<(DateTime<FixedOffset>, String)>> {
+            Ok(self.snapshots.borrow().last().map(|name| (naming().parse(name).unwrap(), name.clone())))
+        }
+        fn snapshot_count(&self) -> Result<usize> {
+            Ok(self.snapshots.borrow().len())
+        }
+        fn has_snapshot(&self, name: &str) -> Result<bool> {
+            Ok(self.snapshots.borrow().iter().any(|snapshot| snapshot == name))
+        }
+        fn create_empty_snapshot(&self, name: &str) -> Result<()> {
+            self.snapshots.borrow_mut().push(name.to_owned());
+            Ok(())
+        }
+        fn extract_changes(&self, _latest: &str, _new: &str) -> Result<Option<ChangeList>> {
+            Ok(self.changes.as_ref().map(|changes| serde_json::from_value(serde_json::to_value(changes).unwrap()).unwrap()))
+        }
+        fn copy_snapshot(&self, _latest: &str, _new: &str, fast_forward: bool) -> Result<()> {
+            self.fast_forwarded.set(Some(fast_forward));
+            Ok(())
+        }
+        fn apply_changes(&self, _latest: &str, _new: &str, _fast_forward: bool) -> Result<()> {
+            Ok(())
+        }
+        fn publish_snapshot(&self, new: &str) -> Result<()> {
+            self.snapshots.borrow_mut().push(new.to_owned());
+            Ok(())
+        }
+        fn file_count(&self, name: &str) -> Result<Option<usize>> {
+            Ok(Some(self.has_snapshot(name)? as usize))
+        }
+        fn has_sidecar(&self, name: &str, ext: &str) -> Result<bool> {
+            Ok(self.sidecars.borrow().contains(&(name.to_owned(), ext.to_owned())))
+        }
+        fn write_sidecar(&self, name: &str, ext: &str, _contents: &[u8]) -> Result<()> {
+            self.sidecars.borrow_mut().push((name.to_owned(), ext.to_owned()));
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingSink(RefCell<Vec<&'static str>>);
+
+    impl EventSink for RecordingSink {
+        fn event(&self, event: &ArchiveEvent) {
+            self.0.borrow_mut().push(match event {
+                ArchiveEvent::Started => "started",
+                ArchiveEvent::DetectedDiff { .. } => "detected-diff",
+                ArchiveEvent::CopiedBase { .. } => "copied-base",
+                ArchiveEvent::AppliedDiff { .. } => "applied-diff",
+                ArchiveEvent::WroteChanges { .. } => "wrote-changes",
+                ArchiveEvent::Finished { .. } => "finished",
+            });
+        }
+    }
+
+    fn memory_backend(changes: Option<ChangeList>) -> MemoryBackend {
+        MemoryBackend {
+            snapshots: RefCell::new(vec![snapshot_name(&naming(), 2)]),
+            sidecars: RefCell::new(Vec::new()),
+            changes,
+            fast_forwarded: Cell::new(None),
+        }
+    }
+
+    #[test]
+    fn archive_empty_snapshots_an_empty_working_dir_without_fast_forwarding() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        // the latest snapshot is from today and not the base, a run would normally fast-forward it
+        let backend = memory_backend(Some(changes(0, 1, 0)));
+        let today = naming().format(&(Local::now() - Duration::minutes(1)));
+        backend.snapshots.borrow_mut().push(today.clone());
+        backend.write_sidecar(&today, CHANGES_EXT, b"").unwrap();
+
+        let e = archive(&backend, &config, &LoggingSink).unwrap_err();
+        assert!(e.to_string().contains("--archive-empty"), "{e}");
+        assert_eq!(backend.fast_forwarded.get(), None);
+
+        config.archive_empty = true;
+        let report = archive(&backend, &config, &LoggingSink).unwrap();
+        assert_eq!(report.outcome, ArchiveOutcome::Created);
+        assert_eq!(backend.fast_forwarded.get(), Some(false));
+
+        // the empty snapshot itself is fast-forwarded by the next regular run
+        fs::write(config.local_working_dir.join("file.txt"), "contents").unwrap();
+        config.archive_empty = false;
+        archive(&backend, &config, &LoggingSink).unwrap();
+        assert_eq!(backend.fast_forwarded.get(), Some(true));
+    }
+
+    #[test]
+    fn events_follow_the_steps_of_a_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        fs::write(config.local_working_dir.join("file.txt"), "contents").unwrap();
+        let backend = memory_backend(Some(changes(1, 0, 0)));
+        let sink = RecordingSink::default();
+        let report = archive(&backend, &config, &sink).unwrap();
+        assert_eq!(report.outcome, ArchiveOutcome::Created);
+        assert_eq!(*sink.0.borrow(), ["started", "detected-diff", "copied-base", "applied-diff", "wrote-changes", "finished"]);
+        assert_eq!(backend.snapshots.borrow().last(), report.snapshot.as_ref());
+    }
+
+    #[test]
+    fn a_run_without_changes_is_only_started_and_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        fs::write(config.local_working_dir.join("file.txt"), "contents").unwrap();
+        let sink = RecordingSink::default();
+        let report = archive(&memory_backend(None), &config, &sink).unwrap();
+        assert_eq!(report.outcome, ArchiveOutcome::NoChanges);
+        assert_eq!(*sink.0.borrow(), ["started", "finished"]);
+    }
+
+    #[test]
+    fn runs_stopped_before_rsync_are_started_and_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        fs::write(config.local_working_dir.join("file.txt"), "contents").unwrap();
+        config.backpressure.min_free = Some(crate::config::FileSize(u64::MAX));
+        let sink = RecordingSink::default();
+        assert_eq!(archive_with_events(&config, &sink).unwrap().outcome, ArchiveOutcome::Deferred);
+        assert_eq!(*sink.0.borrow(), ["started", "finished"]);
+
+        config.backpressure.min_free = None;
+        config.since_last_success = true;
+        write_state(&config.local_archive, &config.local_working_dir, max_mtime(&config.local_working_dir).unwrap()).unwrap();
+        let sink = RecordingSink::default();
+        assert_eq!(archive_with_events(&config, &sink).unwrap().outcome, ArchiveOutcome::NoChanges);
+        assert_eq!(*sink.0.borrow(), ["started", "finished"]);
+    }
+
+    #[test]
+    fn quick_skip_is_defeated_by_touching_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.quick_skip = true;
+        let file = fs::File::create(config.local_working_dir.join("file.txt")).unwrap();
+        let three_days_ago = SystemTime::now() - std::time::Duration::from_secs(3 * 24 * 3600);
+        file.set_modified(three_days_ago).unwrap();
+        fs::File::open(&config.local_working_dir).unwrap().set_modified(three_days_ago).unwrap();
+        let backend = memory_backend(Some(changes(1, 0, 0)));
+        let latest = backend.snapshots.borrow()[0].clone();
+
+        // the initial snapshot has no change list, it is never skipped
+        let report = archive(&backend, &config, &LoggingSink).unwrap();
+        assert_eq!(report.outcome, ArchiveOutcome::Created);
+
+        let backend = memory_backend(Some(changes(1, 0, 0)));
+        backend.write_sidecar(&latest, CHANGES_EXT, b"").unwrap();
+        let report = archive(&backend, &config, &LoggingSink).unwrap();
+        assert_eq!(report.outcome, ArchiveOutcome::Skipped);
+
+        file.set_modified(SystemTime::now()).unwrap();
+        let report = archive(&backend, &config, &LoggingSink).unwrap();
+        assert_eq!(report.outcome, ArchiveOutcome::Created);
+    }
+
+    #[test]
+    fn fast_forward_only_onto_a_snapshot_from_today_with_history() {
+        let today = Local::now().fixed_offset();
+        let yesterday = today - Duration::days(1);
+        // empty archive, nothing to replace
+        assert!(!should_fast_forward(today, 0));
+        // the only snapshot is the base of the archive
+        assert!(!should_fast_forward(today, 1));
+        assert!(!should_fast_forward(yesterday, 1));
+        assert!(should_fast_forward(today, 3));
+        assert!(!should_fast_forward(yesterday, 3));
+    }
+
+    #[test]
+    fn failed_apply_leaves_only_the_staging_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.fast_forward = false;
+        let naming = config.naming();
+        let base = naming.format(&(Local::now() - Duration::days(2)));
+        let now = naming.format(&Local::now());
+        let archive = &config.local_archive;
+        fs::create_dir(archive.join(&base)).unwrap();
+        fs::write(archive.join(&base).join("file.txt"), "contents").unwrap();
+        fs::write(sidecar_path(archive, &now, DIFF_EXT), "not a batch file").unwrap();
+        let changes = ChangeList { deleted: Vec::new(), changed: Vec::new(), created: Vec::new(), moved: Vec::new() };
+        fs::write(sidecar_path(archive, &now, CHANGES_EXT), serde_json::to_string(&changes).unwrap()).unwrap();
+
+        assert!(apply_local(&config, &now).is_err());
+        let staging = archive.join(staging_name(&now));
+        assert_eq!(fs::read_to_string(staging.join("file.txt")).unwrap(), "contents");
+        assert!(!archive.join(&now).exists());
+        assert!(archive.join(&base).is_dir());
+    }
+
+    #[test]
+    fn failed_fast_forward_leaves_no_half_updated_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let naming = config.naming();
+        let base = naming.format(&(Local::now() - Duration::days(2)));
+        let latest = naming.format(&(Local::now() - Duration::seconds(1)));
+        let now = naming.format(&Local::now());
+        let archive = &config.local_archive;
+        fs::create_dir(archive.join(&base)).unwrap();
+        fs::create_dir(archive.join(&latest)).unwrap();
+        fs::write(archive.join(&latest).join("file.txt"), "contents").unwrap();
+        fs::write(sidecar_path(archive, &now, DIFF_EXT), "not a batch file").unwrap();
+        let changes = ChangeList { deleted: Vec::new(), changed: Vec::new(), created: Vec::new(), moved: Vec::new() };
+        fs::write(sidecar_path(archive, &now, CHANGES_EXT), serde_json::to_string(&changes).unwrap()).unwrap();
+
+        assert!(apply_local(&config, &now).is_err());
+        let staging = archive.join(staging_name(&now));
+        assert_eq!(fs::read_to_string(staging.join("file.txt")).unwrap(), "contents");
+        assert!(!archive.join(&now).exists());
+        assert!(!archive.join(&latest).exists());
+        assert_eq!(latest_snapshot_dir(archive, &naming).unwrap().map(|(_, name)| name), Some(base));
+    }
+
+    /// Empty snapshot recorded with `merkle_root` in its `.meta.json`.
+    fn snapshot_with_root(config: &Config, name: &str, merkle_root: Option<String>) {
+        fs::create_dir(config.local_archive.join(name)).unwrap();
+        write_meta(config, name, merkle_root);
+    }
+
+    fn write_meta(config: &Config, name: &str, merkle_root: Option<String>) {
+        let mut meta = SnapshotMeta::new(config, Local::now(), Vec::new(), name, None).unwrap();
+        meta.merkle_root = merkle_root;
+        fs::write(sidecar_path(&config.local_archive, name, META_EXT), serde_json::to_string(&meta).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn latest_matching_merkle_root_compares_with_the_recorded_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let naming = config.naming();
+        fs::write(config.local_working_dir.join("file.txt"), "contents").unwrap();
+        let live = snapshot_merkle_root(&config.local_working_dir, &ExcludeMatcher::load(&config.exclude).unwrap()).unwrap();
+        let older = naming.format(&(Local::now() - Duration::days(2)));
+        let latest = naming.format(&(Local::now() - Duration::days(1)));
+        snapshot_with_root(&config, &older, Some(live.to_hex().to_string()));
+        snapshot_with_root(&config, &latest, None);
+        // only the latest snapshot counts, it has no root
+        assert_eq!(latest_matching_merkle_root(&config).unwrap(), None);
+
+        write_meta(&config, &latest, Some(live.to_hex().to_string()));
+        assert_eq!(latest_matching_merkle_root(&config).unwrap(), Some(latest));
+
+        fs::write(config.local_working_dir.join("file.txt"), "changed").unwrap();
+        assert_eq!(latest_matching_merkle_root(&config).unwrap(), None);
+    }
+
+    #[test]
+    fn verify_merkle_root_notices_a_changed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let naming = config.naming();
+        let name = naming.format(&Local::now());
+        let unrecorded = naming.format(&(Local::now() - Duration::days(1)));
+        snapshot_with_root(&config, &unrecorded, None);
+        assert_eq!(verify_merkle_root(&config, &unrecorded).unwrap(), None);
+
+        let snapshot = config.local_archive.join(&name);
+        fs::create_dir(&snapshot).unwrap();
+        fs::write(snapshot.join("file.txt"), "contents").unwrap();
+        let root = snapshot_merkle_root(&snapshot, &ExcludeMatcher::load(&config.exclude).unwrap()).unwrap();
+        write_meta(&config, &name, Some(root.to_hex().to_string()));
+        assert_eq!(verify_merkle_root(&config, &name).unwrap(), Some(true));
+
+        fs::write(snapshot.join("file.txt"), "contentz").unwrap();
+        assert_eq!(verify_merkle_root(&config, &name).unwrap(), Some(false));
+    }
+
+    #[test]
+    fn verify_retained_fails_on_a_corrupt_kept_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let naming = config.naming();
+        let excludes = ExcludeMatcher::load(&config.exclude).unwrap();
+        let names: Vec<_> = [3, 2, 1].iter().map(|&days| naming.format(&(Local::now() - Duration::days(days)))).collect();
+        for name in &names {
+            let snapshot = config.local_archive.join(name);
+            fs::create_dir(&snapshot).unwrap();
+            fs::write(snapshot.join("file.txt"), format!("contents of {name}")).unwrap();
+            write_meta(&config, name, Some(snapshot_merkle_root(&snapshot, &excludes).unwrap().to_hex().to_string()));
+        }
+        let policy = RetentionPolicy { keep_last: Some(2), ..Default::default() };
+        verify_retained(&config, &policy).unwrap();
+
+        // the snapshot prune removes isn't checked
+        fs::write(config.local_archive.join(&names[0]).join("file.txt"), "corrupt").unwrap();
+        verify_retained(&config, &policy).unwrap();
+
+        let kept = config.local_archive.join(&names[1]).join("file.txt");
+        let mut contents = fs::read(&kept).unwrap();
+        contents[0] ^= 1;
+        fs::write(&kept, contents).unwrap();
+        let e = verify_retained(&config, &policy).unwrap_err();
+        assert!(e.to_string().contains(&names[1]), "{e}");
+    }
+
+    #[test]
+    fn backpressure_defers_the_run_before_anything_is_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.backpressure.min_free = Some(crate::config::FileSize(u64::MAX));
+        fs::write(config.local_working_dir.join("file.txt"), "contents").unwrap();
+        let report = archive_local(&config).unwrap();
+        assert_eq!(report.outcome, ArchiveOutcome::Deferred);
+        assert_eq!(fs::read_dir(&config.local_archive).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn since_last_success_skips_an_unmodified_working_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.since_last_success = true;
+        fs::write(config.local_working_dir.join("file.txt"), "contents").unwrap();
+        let mtime = max_mtime(&config.local_working_dir).unwrap();
+        write_state(&config.local_archive, &config.local_working_dir, mtime).unwrap();
+
+        let report = archive_local(&config).unwrap();
+        assert_eq!(report.outcome, ArchiveOutcome::NoChanges);
+        assert!(latest_snapshot_dir(&config.local_archive, &config.naming()).unwrap().is_none());
+    }
+
+    #[test]
+    fn since_last_success_state_is_invalidated_by_a_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.since_last_success = true;
+        let file = fs::File::create(config.local_working_dir.join("file.txt")).unwrap();
+        let recorded = max_mtime(&config.local_working_dir).unwrap();
+        write_state(&config.local_archive, &config.local_working_dir, recorded).unwrap();
+        file.set_modified(recorded + std::time::Duration::from_secs(1)).unwrap();
+
+        // past the gate, the run creates the base snapshot and runs rsync, which may not be installed
+        let result = archive_local(&config);
+        assert!(!matches!(&result, Ok(report) if report.outcome == ArchiveOutcome::NoChanges));
+        let snapshots = count_timestamp_named_folders(&config.local_archive, &config.naming()).unwrap();
+        assert_eq!(snapshots, if result.is_ok() { 2 } else { 1 });
+        let state = read_state(&config.local_archive, &config.local_working_dir).unwrap();
+        assert_eq!(state.source_max_mtime > recorded, result.is_ok());
+    }
+
+    #[test]
+    fn since_last_success_runs_after_a_modification() {
+        if !rsync_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.since_last_success = true;
+        let file = config.local_working_dir.join("file.txt");
+        fs::write(&file, "contents").unwrap();
+        let before = SystemTime::now() - std::time::Duration::from_secs(60);
+        write_state(&config.local_archive, &config.local_working_dir, before).unwrap();
+
+        let report = archive_local(&config).unwrap();
+        assert_eq!(report.outcome, ArchiveOutcome::Created);
+        let state = read_state(&config.local_archive, &config.local_working_dir).unwrap();
+        assert!(state.source_max_mtime > before);
+    }
+
+    #[test]
+    fn copy_links_archives_the_target_of_a_symlinked_folder() {
+        if !rsync_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.copy_links = true;
+        let target = dir.path().join("elsewhere");
+        fs::create_dir(&target).unwrap();
+        fs::write(target.join("file.txt"), "contents").unwrap();
+        std::os::unix::fs::symlink(&target, config.local_working_dir.join("linked")).unwrap();
+
+        let report = archive_local(&config).unwrap();
+        let linked = report.snapshot_path.unwrap().join("linked");
+        assert!(fs::symlink_metadata(&linked).unwrap().is_dir());
+        assert_eq!(fs::read_to_string(linked.join("file.txt")).unwrap(), "contents");
+    }
+
+    #[test]
+    fn unchanged_working_dir_leaves_no_batch_or_change_list() {
+        if !rsync_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.min_interval = None;
+        fs::write(config.local_working_dir.join("file.txt"), "contents").unwrap();
+        assert_eq!(archive_local(&config).unwrap().outcome, ArchiveOutcome::Created);
+        let before: Vec<_> = fs::read_dir(&config.local_archive).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+
+        assert_eq!(archive_local(&config).unwrap().outcome, ArchiveOutcome::NoChanges);
+        let after: Vec<_> = fs::read_dir(&config.local_archive).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        let added: Vec<_> = after.iter().filter(|name| !before.contains(name)).collect();
+        assert!(added.is_empty(), "{added:?}");
+    }
+
+    #[test]
+    fn extract_leaves_out_a_nested_archive() {
+        if !rsync_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.local_archive = config.local_working_dir.join("archive");
+        config.auto_exclude_archive = true;
+        fs::create_dir(&config.local_archive).unwrap();
+        fs::write(config.local_working_dir.join("file.txt"), "contents").unwrap();
+        let temp_dir = LazyTempDir::new(None);
+        let config = config.effective_excludes(&temp_dir).unwrap();
+
+        let name = extract_local(&config).unwrap().expect("file.txt is new");
+        let changes = fs::read_to_string(sidecar_path(&config.local_archive, &name, CHANGES_EXT)).unwrap();
+        let changes: ChangeList = serde_json::from_str(&changes).unwrap();
+        let paths: Vec<_> = changes.changed.iter().map(|entity| entity.path()).collect();
+        assert!(paths.contains(&Path::new("file.txt")), "{paths:?}");
+        assert!(paths.iter().all(|path| !path.starts_with("archive")), "{paths:?}");
+    }
+}
@@ -0,0 +1,470 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::os::unix::fs::PermissionsExt;
+use chrono::{DateTime, FixedOffset};
+use anyhow::{anyhow, Context, Result};
+use tracing::{info, warn};
+use crate::config::{Config, SnapshotStrategy};
+use crate::gc::split_sidecar_name;
+use crate::manifest::{snapshot_merkle_root, ExcludeMatcher};
+use crate::syncer_util::{ChangeList, RsyncOptions, count_timestamp_named_folders, latest_snapshot_dir, rsync_apply_diff, rsync_extract_diff, rsync_transfer, sidecar_path, compress_diff_file, find_diff_file, remove_batch_files, with_plain_diff_file, RsyncDirection, staging_name, DIFF_EXT, DIFF_ZST_EXT, INCOMPLETE_EXT, STAGING_PREFIX};
+use crate::util::{absolute_path, check_free_space, concat_str_os, dir_size, same_device, CpMvMode, FsDeviceIds, fs_copy, fs_move, walk_files};
+
+/// Storage the snapshots are kept in. Snapshots and their sidecar files are addressed by name.
+pub trait Backend {
+    /// Timestamp and name of the latest snapshot
+    fn latest_snapshot(&self) -> Result<Option<(DateTime<FixedOffset>, String)>>;
+    fn snapshot_count(&self) -> Result<usize>;
+    fn has_snapshot(&self, name: &str) -> Result<bool>;
+    /// Creates the empty base snapshot of a new archive
+    fn create_empty_snapshot(&self, name: &str) -> Result<()>;
+    /// Diffs the working dir against snapshot `latest`, `new` is the name of the snapshot about to be created
+    fn extract_changes(&self, latest: &str, new: &str) -> Result<Option<ChangeList>>;
+    /// False if renaming `latest` is not cheap, fast-forwarding is turned off then
+    fn can_rename_snapshot(&self, _latest: &str) -> bool {
+        true
+    }
+    /// Fails if copying `latest` would likely run out of space
+    fn check_free_space(&self, _latest: &str) -> Result<()> {
+        Ok(())
+    }
+    /// Makes `new` a copy of `latest`, or renames `latest` to `new` when fast-forwarding.
+    /// Either is done under a staging name, `new` only gets its name in `publish_snapshot`.
+    /// A copy interrupted by an earlier run may be resumed instead of starting over.
+    fn copy_snapshot(&self, latest: &str, new: &str, fast_forward: bool) -> Result<()>;
+    /// Brings `new` in line with the working dir, after `copy_snapshot`
+    fn apply_changes(&self, latest: &str, new: &str, fast_forward: bool) -> Result<()>;
+    /// Makes `new` visible under its name once it and its sidecars are complete, backends that build snapshots
+    /// in place have nothing to do
+    fn publish_snapshot(&self, _new: &str) -> Result<()> {
+        Ok(())
+    }
+    /// `snapshot_merkle_root` of `name` before it is published, None if the backend can't compute it
+    fn merkle_root(&self, _name: &str) -> Result<Option<blake3::Hash>> {
+        Ok(None)
+    }
+    /// Number of files in snapshot `name`, None if the backend can't count them cheaply
+    fn file_count(&self, _name: &str) -> Result<Option<usize>> {
+        Ok(None)
+    }
+    /// Removes what `extract_changes` wrote for `new` when the snapshot won't be created after all
+    fn discard_changes(&self, _new: &str) -> Result<()> {
+        Ok(())
+    }
+    fn has_sidecar(&self, name: &str, ext: &str) -> Result<bool>;
+    fn write_sidecar(&self, name: &str, ext: &str, contents: &[u8]) -> Result<()>;
+    /// Command lines run so far, recorded in the `.meta.json` sidecar
+    fn commands(&self) -> Vec<String> {
+        vec![]
+    }
+    /// Command lines and their output so far, for the `.rsynclog` sidecar
+    fn command_output(&self) -> String {
+        String::new()
+    }
+}
+
+/// Snapshots are folders in `local_archive`, diffs are rsync batch files next to them.
+pub struct FsBackend<'a> {
+    pub config: &'a Config,
+    pub rsync_options: RsyncOptions,
+}
+
+impl<'a> FsBackend<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        FsBackend { config, rsync_options: config.rsync_options() }
+    }
+
+    /// Applies `snapshot_mode` to the snapshot folder at `path`.
+    fn set_snapshot_mode(&self, path: &Path) -> Result<()> {
+        if let Some(mode) = self.config.snapshot_mode() {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode)).context(format!("setting mode of {path:?}"))?;
+        }
+        Ok(())
+    }
+
+    /// Creates the date folders `name` is nested in with the hierarchical layout.
+    fn create_parent(&self, name: &str) -> Result<()> {
+        let path = self.config.local_archive.join(name);
+        match path.parent() {
+            Some(parent) => fs::create_dir_all(parent).context(format!("creating {parent:?}")),
+            None => Ok(())
+        }
+    }
+
+    /// Folder snapshot `new` is built in until `publish_snapshot`. A copy-apply fast-forward renames the latest
+    /// snapshot to it, so a failed apply never leaves a half updated snapshot under a snapshot name.
+    fn build_dir(&self, new: &str) -> PathBuf {
+        self.config.local_archive.join(staging_name(new))
+    }
+
+    /// Folder left behind by an interrupted snapshot build: a staging folder, or a snapshot folder with an
+    /// `.incomplete` marker written by older versions. Stale markers without a folder are removed.
+    fn find_interrupted_build(&self) -> Result<Option<String>> {
+        let local_archive = &self.config.local_archive;
+        let naming = self.config.naming();
+        for entry in fs::read_dir(local_archive).context("unable to read local archive")? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let file_name = match file_name.to_str() {
+                Some(name) => name,
+                None => continue
+            };
+            if file_name.strip_prefix(STAGING_PREFIX).is_some_and(|name| naming.parse(name).is_some()) && entry.path().is_dir() {
+                return Ok(Some(file_name.to_owned()));
+            }
+            let name = match split_sidecar_name(file_name) {
+                Some((name, INCOMPLETE_EXT)) if naming.parse(name).is_some() => name,
+                _ => continue
+            };
+            if local_archive.join(name).is_dir() {
+                return Ok(Some(name.to_owned()));
+            }
+            fs::remove_file(entry.path()).context(format!("removing {:?}", entry.path()))?;
+        }
+        Ok(None)
+    }
+
+    /// Moves the folder of an interrupted build to the staging folder of `new` and removes the marker and batch
+    /// file of the interrupted run.
+    fn take_over(&self, interrupted: &str, new: &str) -> Result<PathBuf> {
+        let local_archive = &self.config.local_archive;
+        let staging = self.build_dir(new);
+        fs::rename(local_archive.join(interrupted), &staging).context(format!("renaming {interrupted}"))?;
+        let name = interrupted.strip_prefix(STAGING_PREFIX).unwrap_or(interrupted);
+        for ext in [INCOMPLETE_EXT, DIFF_EXT, DIFF_ZST_EXT] {
+            let stale = sidecar_path(local_archive, name, ext);
+            if name != new && stale.exists() {
+                fs::remove_file(&stale).context(format!("removing {stale:?}"))?;
+            }
+        }
+        Ok(staging)
+    }
+
+    /// Continues an interrupted build in folder `interrupted`: moves it to the staging folder of `new` and lets
+    /// rsync copy the rest of `latest`, files that are already there are skipped.
+    fn resume_copy(&self, interrupted: &str, latest: &str, new: &str) -> Result<()> {
+        info!("resuming interrupted copy of {latest} in {interrupted}");
+        let staging = self.take_over(interrupted, new)?;
+        rsync_transfer(RsyncDirection::LocalToLocal {
+            from: self.config.local_archive.join(latest),
+            to: staging
+        }, &self.config.exclude, &[OsString::from("--delete")], &self.rsync_options)?;
+        Ok(())
+    }
+}
+
+impl Backend for FsBackend<'_> {
+    fn latest_snapshot(&self) -> Result<Option<(DateTime<FixedOffset>, String)>> {
+        latest_snapshot_dir(&self.config.local_archive, &self.config.naming())
+    }
+
+    fn snapshot_count(&self) -> Result<usize> {
+        count_timestamp_named_folders(&self.config.local_archive, &self.config.naming())
+    }
+
+    fn has_snapshot(&self, name: &str) -> Result<bool> {
+        Ok(self.config.naming().parse(name).is_some() && self.config.local_archive.join(name).is_dir())
+    }
+
+    fn create_empty_snapshot(&self, name: &str) -> Result<()> {
+        let path = self.config.local_archive.join(name);
+        self.create_parent(name)?;
+        fs::create_dir(&path)?;
+        self.set_snapshot_mode(&path)
+    }
+
+    fn extract_changes(&self, latest: &str, new: &str) -> Result<Option<ChangeList>> {
+        let latest_archived_path = self.config.local_archive.join(latest);
+        let rsync_dir = RsyncDirection::LocalToLocal {
+            from: self.config.source_dir().to_path_buf(),
+            to: latest_archived_path.clone()
+        };
+        let diff = if self.config.snapshot_strategy == SnapshotStrategy::LinkDest {
+            rsync_extract_diff(rsync_dir, None, &self.config.exclude, &self.rsync_options)?
+        } else {
+            let diff_filepath = sidecar_path(&self.config.local_archive, new, DIFF_EXT);
+            let diff = rsync_extract_diff(rsync_dir, Some(&diff_filepath), &self.config.exclude, &self.rsync_options)?;
+            match diff {
+                Some(_) if self.config.compress_diffs => { compress_diff_file(&diff_filepath)?; }
+                Some(_) => {}
+                None => remove_batch_files(&diff_filepath)?,
+            }
+            diff
+        };
+        Ok(diff.map(|mut changed| {
+            info!("changed raw: {changed:?}");
+            changed.extract_moves(&latest_archived_path, self.config.source_dir(), &self.config.move_detection());
+            info!("try find moved files: {changed:?}");
+            changed
+        }))
+    }
+
+    fn can_rename_snapshot(&self, latest: &str) -> bool {
+        if self.config.cas {
+            info!("files of {latest} are shared through the cas pool, not fast-forwarding");
+            return false;
+        }
+        let local_archive = &self.config.local_archive;
+        let latest_archived_path = local_archive.join(latest);
+        match same_device(&FsDeviceIds, &latest_archived_path, local_archive) {
+            Ok(true) => true,
+            Ok(false) => {
+                warn!("{latest_archived_path:?} is on a different device than {local_archive:?}, mv would copy everything, not fast-forwarding");
+                false
+            }
+            Err(e) => {
+                warn!("unable to compare devices of {latest_archived_path:?} and {local_archive:?}, not fast-forwarding: {e}");
+                false
+            }
+        }
+    }
+
+    fn check_free_space(&self, latest: &str) -> Result<()> {
+        if self.config.snapshot_strategy == SnapshotStrategy::LinkDest {
+            // only changed files take space
+            return Ok(());
+        }
+        let local_archive = &self.config.local_archive;
+        let latest_archived_path = local_archive.join(latest);
+        let needed = dir_size(&latest_archived_path).context(format!("estimating size of {latest_archived_path:?}"))?;
+        let available = fs2::available_space(local_archive).context(format!("querying free space of {local_archive:?}"))?;
+        let margin = self.config.min_free.map_or(0, |size| size.0);
+        check_free_space(local_archive, available, needed, margin)
+    }
+
+    fn copy_snapshot(&self, latest: &str, new: &str, fast_forward: bool) -> Result<()> {
+        let local_archive = &self.config.local_archive;
+        let latest_archived_path = local_archive.join(latest);
+        match self.config.snapshot_strategy {
+            SnapshotStrategy::CopyApply => {
+                if fast_forward {
+                    info!("fast-forwarding by renaming latest archived folder");
+                    fs_move(&latest_archived_path, local_archive, CpMvMode::FolderRename(staging_name(new)))?;
+                } else if let Some(interrupted) = self.find_interrupted_build()? {
+                    self.resume_copy(&interrupted, latest, new)?;
+                } else {
+                    info!("copying latest archived folder");
+                    fs_copy(&latest_archived_path, local_archive, CpMvMode::FolderRename(staging_name(new)))?;
+                }
+            }
+            SnapshotStrategy::CopyDest | SnapshotStrategy::LinkDest => {
+                // unchanged files are copied or linked by rsync in apply_changes, which also finishes an
+                // interrupted transfer
+                if let Some(interrupted) = self.find_interrupted_build()? {
+                    info!("resuming interrupted transfer in {interrupted}");
+                    self.take_over(&interrupted, new)?;
+                } else {
+                    fs::create_dir(self.build_dir(new))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_changes(&self, latest: &str, new: &str, fast_forward: bool) -> Result<()> {
+        let local_archive = &self.config.local_archive;
+        let latest_archived_path = local_archive.join(latest);
+        let new_latest_archived = self.build_dir(new);
+        match self.config.snapshot_strategy {
+            SnapshotStrategy::CopyApply => {
+                info!("applying diff file");
+                let diff_file = find_diff_file(local_archive, new).ok_or(anyhow!("batch file of {new} is missing"))?;
+                with_plain_diff_file(&diff_file, |diff_file| {
+                    rsync_apply_diff(&new_latest_archived, diff_file, &self.config.exclude, &self.rsync_options.backup_args(latest), &self.rsync_options)
+                })?;
+            }
+            SnapshotStrategy::CopyDest | SnapshotStrategy::LinkDest => {
+                let option = match self.config.snapshot_strategy {
+                    SnapshotStrategy::LinkDest => "--link-dest=",
+                    _ => "--copy-dest=",
+                };
+                info!("transferring into an empty folder with the latest archived folder as {option}");
+                let mut extra_args = self.rsync_options.to_args();
+                // only matters for a resumed transfer, which may hold files deleted from the working dir since
+                extra_args.push(OsString::from("--delete"));
+                extra_args.push(concat_str_os(option, &absolute_path(&latest_archived_path)?));
+                rsync_transfer(RsyncDirection::LocalToLocal {
+                    from: self.config.source_dir().to_path_buf(),
+                    to: new_latest_archived.clone()
+                }, &self.config.exclude, &extra_args, &self.rsync_options)?;
+                if fast_forward {
+                    info!("fast-forwarding by removing latest archived folder");
+                    fs::remove_dir_all(&latest_archived_path)?;
+                }
+            }
+        }
+
+        self.set_snapshot_mode(&new_latest_archived)?;
+
+        if self.config.cas {
+            #[cfg(feature = "cas")]
+            crate::cas::dedup_snapshot(local_archive, &new_latest_archived)?;
+            #[cfg(not(feature = "cas"))]
+            warn!("cas = true, but built without the cas feature");
+        }
+        Ok(())
+    }
+
+    fn publish_snapshot(&self, name: &str) -> Result<()> {
+        let local_archive = &self.config.local_archive;
+        let staging = local_archive.join(staging_name(name));
+        if staging.is_dir() {
+            self.create_parent(name)?;
+            fs::rename(&staging, local_archive.join(name)).context(format!("renaming {staging:?}"))?;
+        }
+        Ok(())
+    }
+
+    fn merkle_root(&self, name: &str) -> Result<Option<blake3::Hash>> {
+        let local_archive = &self.config.local_archive;
+        let staging = local_archive.join(staging_name(name));
+        let dir = if staging.is_dir() { staging } else { local_archive.join(name) };
+        let excludes = ExcludeMatcher::load(&self.config.exclude)?;
+        Ok(Some(snapshot_merkle_root(&dir, &excludes)?))
+    }
+
+    fn file_count(&self, name: &str) -> Result<Option<usize>> {
+        let dir = self.config.local_archive.join(name);
+        Ok(Some(walk_files(&dir).context(format!("walking {dir:?}"))?.len()))
+    }
+
+    fn discard_changes(&self, new: &str) -> Result<()> {
+        let local_archive = &self.config.local_archive;
+        remove_batch_files(&sidecar_path(local_archive, new, DIFF_EXT))?;
+        let compressed = sidecar_path(local_archive, new, DIFF_ZST_EXT);
+        if compressed.exists() {
+            fs::remove_file(&compressed).context(format!("removing {compressed:?}"))?;
+        }
+        Ok(())
+    }
+
+    fn has_sidecar(&self, name: &str, ext: &str) -> Result<bool> {
+        let path = sidecar_path(&self.config.local_archive, name, ext);
+        path.try_exists().context(format!("checking for {path:?}"))
+    }
+
+    fn write_sidecar(&self, name: &str, ext: &str, contents: &[u8]) -> Result<()> {
+        let path = sidecar_path(&self.config.local_archive, name, ext);
+        fs::write(&path, contents).context(format!("writing {path:?}"))
+    }
+
+    fn commands(&self) -> Vec<String> {
+        self.rsync_options.command_log.commands()
+    }
+
+    fn command_output(&self) -> String {
+        self.rsync_options.command_log.output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Local};
+    use crate::test_support::{rsync_available, test_config};
+
+    #[test]
+    fn cas_snapshots_are_never_renamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        let latest = config.naming().format(&Local::now());
+        fs::create_dir(config.local_archive.join(&latest)).unwrap();
+        assert!(FsBackend::new(&config).can_rename_snapshot(&latest));
+        config.cas = true;
+        assert!(!FsBackend::new(&config).can_rename_snapshot(&latest));
+    }
+
+    #[test]
+    fn incomplete_snapshots_are_not_the_latest() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let naming = config.naming();
+        let archive = &config.local_archive;
+        let (complete, incomplete) = (naming.format(&(Local::now() - Duration::days(1))), naming.format(&Local::now()));
+        fs::create_dir(archive.join(&complete)).unwrap();
+        fs::create_dir(archive.join(&incomplete)).unwrap();
+        fs::write(sidecar_path(archive, &incomplete, INCOMPLETE_EXT), "").unwrap();
+        fs::create_dir(archive.join(staging_name(&naming.format(&(Local::now() + Duration::days(1)))))).unwrap();
+
+        let backend = FsBackend::new(&config);
+        assert_eq!(backend.latest_snapshot().unwrap().unwrap().1, complete);
+        assert_eq!(backend.snapshot_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn interrupted_builds_are_found_and_stale_markers_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let naming = config.naming();
+        let archive = &config.local_archive;
+        let backend = FsBackend::new(&config);
+        let stale = sidecar_path(archive, &naming.format(&(Local::now() - Duration::days(2))), INCOMPLETE_EXT);
+        fs::write(&stale, "").unwrap();
+        assert_eq!(backend.find_interrupted_build().unwrap(), None);
+        assert!(!stale.exists());
+
+        let legacy = naming.format(&(Local::now() - Duration::days(1)));
+        fs::create_dir(archive.join(&legacy)).unwrap();
+        fs::write(sidecar_path(archive, &legacy, INCOMPLETE_EXT), "").unwrap();
+        assert_eq!(backend.find_interrupted_build().unwrap(), Some(legacy.clone()));
+
+        fs::remove_dir(archive.join(&legacy)).unwrap();
+        let staging = staging_name(&naming.format(&Local::now()));
+        fs::create_dir(archive.join(&staging)).unwrap();
+        assert_eq!(backend.find_interrupted_build().unwrap(), Some(staging));
+    }
+
+    #[test]
+    fn copy_resumes_an_interrupted_copy() {
+        if !rsync_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.fast_forward = false;
+        let naming = config.naming();
+        let latest = naming.format(&(Local::now() - Duration::days(2)));
+        let interrupted = naming.format(&(Local::now() - Duration::days(1)));
+        let new = naming.format(&Local::now());
+        let archive = &config.local_archive;
+        fs::create_dir(archive.join(&latest)).unwrap();
+        for file in ["copied.txt", "missing.txt"] {
+            fs::write(archive.join(&latest).join(file), file).unwrap();
+        }
+        fs::create_dir(archive.join(&interrupted)).unwrap();
+        fs::write(archive.join(&interrupted).join("copied.txt"), "copied.txt").unwrap();
+        fs::write(archive.join(&interrupted).join("partial.tmp"), "").unwrap();
+        fs::write(sidecar_path(archive, &interrupted, INCOMPLETE_EXT), "").unwrap();
+
+        FsBackend::new(&config).copy_snapshot(&latest, &new, false).unwrap();
+        let staging = archive.join(staging_name(&new));
+        assert_eq!(fs::read_to_string(staging.join("missing.txt")).unwrap(), "missing.txt");
+        assert!(!staging.join("partial.tmp").exists());
+        assert!(!archive.join(&interrupted).exists());
+        assert!(!sidecar_path(archive, &interrupted, INCOMPLETE_EXT).exists());
+    }
+
+    #[test]
+    fn link_dest_build_resumes_an_interrupted_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        config.snapshot_strategy = SnapshotStrategy::LinkDest;
+        let naming = config.naming();
+        let latest = naming.format(&(Local::now() - Duration::days(2)));
+        let interrupted = naming.format(&(Local::now() - Duration::days(1)));
+        let new = naming.format(&Local::now());
+        let archive = &config.local_archive;
+        fs::create_dir(archive.join(&latest)).unwrap();
+        fs::create_dir(archive.join(staging_name(&interrupted))).unwrap();
+        fs::write(archive.join(staging_name(&interrupted)).join("file.txt"), "contents").unwrap();
+        fs::write(sidecar_path(archive, &interrupted, DIFF_EXT), "stale").unwrap();
+
+        let backend = FsBackend::new(&config);
+        backend.copy_snapshot(&latest, &new, false).unwrap();
+        let staging = archive.join(staging_name(&new));
+        assert_eq!(fs::read_to_string(staging.join("file.txt")).unwrap(), "contents");
+        assert!(!archive.join(staging_name(&interrupted)).exists());
+        assert!(!sidecar_path(archive, &interrupted, DIFF_EXT).exists());
+    }
+}
@@ -0,0 +1,92 @@
+use std::path::Path;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::config::FileSize;
+
+/// `[backpressure]` section: conditions under which an archive run is skipped with a warning instead of making
+/// things worse on a stressed system.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BackpressureConfig {
+    /// Skip while the 1 minute load average is above this. Only checked on Linux, through `/proc/loadavg`
+    pub max_load: Option<f64>,
+    /// Skip while the archive filesystem has less free space than this, e.g. `10G`. Unlike the top level
+    /// `min_free`, which aborts a run that would not fit, this defers it to the next one.
+    pub min_free: Option<FileSize>,
+}
+
+/// Why an archive run should be skipped now, None if it can go ahead.
+pub fn check_backpressure(config: &BackpressureConfig, local_archive: &Path) -> Result<Option<String>> {
+    if let Some(max_load) = config.max_load {
+        if let Some(load) = load_average()? {
+            if load > max_load {
+                return Ok(Some(format!("load average {load} is above max_load = {max_load}")));
+            }
+        }
+    }
+    if let Some(FileSize(min_free)) = config.min_free {
+        let available = fs2::available_space(local_archive).context(format!("querying free space of {local_archive:?}"))?;
+        if available < min_free {
+            return Ok(Some(format!("{available} bytes free in {local_archive:?}, less than [backpressure] min_free = {min_free}")));
+        }
+    }
+    Ok(None)
+}
+
+/// 1 minute load average.
+#[cfg(target_os = "linux")]
+fn load_average() -> Result<Option<f64>> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg").context("reading /proc/loadavg")?;
+    Ok(Some(parse_loadavg(&loadavg)?))
+}
+
+/// First field of `/proc/loadavg`, e.g. `0.52 0.58 0.59 1/389 12345`.
+#[cfg(target_os = "linux")]
+fn parse_loadavg(loadavg: &str) -> Result<f64> {
+    let load = loadavg.split_whitespace().next().unwrap_or_default();
+    load.parse().context(format!("parsing /proc/loadavg {loadavg:?}"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn load_average() -> Result<Option<f64>> {
+    tracing::warn!("[backpressure] max_load is only supported on Linux, ignoring it");
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_go_ahead_without_limits_or_below_them() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(check_backpressure(&BackpressureConfig::default(), dir.path()).unwrap(), None);
+        let config = BackpressureConfig { max_load: None, min_free: Some(FileSize(0)) };
+        assert_eq!(check_backpressure(&config, dir.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn too_little_free_space_defers_the_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackpressureConfig { max_load: None, min_free: Some(FileSize(u64::MAX)) };
+        let reason = check_backpressure(&config, dir.path()).unwrap().unwrap();
+        assert!(reason.contains("min_free"), "{reason}");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn loadavg_is_parsed() {
+        assert_eq!(parse_loadavg("0.52 0.58 0.59 1/389 12345\n").unwrap(), 0.52);
+        assert_eq!(parse_loadavg("12.00 8.10 4.05 9/1024 777").unwrap(), 12.0);
+        assert!(parse_loadavg("").is_err());
+        assert!(parse_loadavg("busy 1 2").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn high_load_defers_the_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BackpressureConfig { max_load: Some(-1.0), min_free: None };
+        let reason = check_backpressure(&config, dir.path()).unwrap().unwrap();
+        assert!(reason.contains("max_load"), "{reason}");
+    }
+}
@@ -0,0 +1,156 @@
+//! Content-addressable dedup pool.
+//!
+//! Every regular file of a snapshot is replaced by a hard link to `.cas/<key>` in the archive, so identical
+//! files are stored once across all snapshots. Hard linked files share their inode, so the key covers the
+//! permissions, owner and mtime along with the contents, and files differing only in those are pooled apart.
+//! rsync replaces changed files instead of writing into them, but it changes attributes in place, so snapshots
+//! are never fast-forwarded with `cas`: the renamed snapshot's files are pool entries other snapshots link to.
+//! `gc` removes pool entries that are no longer linked from any snapshot. Nothing checks pool entries
+//! against their key, a corrupted entry corrupts every snapshot linking to it.
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use tracing::{debug, info};
+use crate::util::{hash_file, walk_files};
+
+pub const POOL_DIR: &str = ".cas";
+
+fn pool_path(local_archive: &Path, key: &blake3::Hash) -> PathBuf {
+    let hex = key.to_hex();
+    local_archive.join(POOL_DIR).join(&hex[..2]).join(hex.as_str())
+}
+
+/// Pool key of `file`: its content hash along with everything hard links share besides the contents.
+fn pool_key(file: &Path, metadata: &fs::Metadata) -> Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(hash_file(file)?.as_bytes());
+    hasher.update(&metadata.mode().to_le_bytes());
+    hasher.update(&metadata.uid().to_le_bytes());
+    hasher.update(&metadata.gid().to_le_bytes());
+    hasher.update(&metadata.mtime().to_le_bytes());
+    hasher.update(&metadata.mtime_nsec().to_le_bytes());
+    Ok(hasher.finalize())
+}
+
+/// Replaces every file of `snapshot` with a hard link into the pool, adding new contents to the pool.
+/// Returns the number of files that were already in the pool.
+pub fn dedup_snapshot(local_archive: &Path, snapshot: &Path) -> Result<usize> {
+    let mut deduplicated = 0;
+    for file in walk_files(snapshot).context(format!("walking {snapshot:?}"))? {
+        let metadata = fs::metadata(&file).context(format!("reading metadata of {file:?}"))?;
+        let pooled = pool_path(local_archive, &pool_key(&file, &metadata)?);
+        match fs::metadata(&pooled) {
+            Ok(pooled_metadata) => {
+                if pooled_metadata.ino() == metadata.ino() && pooled_metadata.dev() == metadata.dev() {
+                    continue;
+                }
+                debug!("{file:?} is already pooled");
+                fs::remove_file(&file).context(format!("removing {file:?}"))?;
+                fs::hard_link(&pooled, &file).context(format!("linking {file:?} to {pooled:?}"))?;
+                deduplicated += 1;
+            }
+            Err(_) => {
+                if let Some(parent) = pooled.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::hard_link(&file, &pooled).context(format!("adding {file:?} to the pool"))?;
+            }
+        }
+    }
+    info!("{deduplicated} files deduplicated");
+    Ok(deduplicated)
+}
+
+/// Lists pool entries no snapshot links to anymore.
+pub fn find_pool_garbage(local_archive: &Path) -> Result<Vec<PathBuf>> {
+    let pool = local_archive.join(POOL_DIR);
+    if !pool.exists() {
+        return Ok(vec![]);
+    }
+    let mut garbage = Vec::new();
+    for file in walk_files(&pool)? {
+        if fs::metadata(&file)?.nlink() == 1 {
+            garbage.push(file);
+        }
+    }
+    Ok(garbage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, SystemTime};
+
+    fn write_file(path: &Path, contents: &str, mode: u32) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        fs::File::options().write(true).open(path).unwrap().set_modified(mtime).unwrap();
+    }
+
+    #[test]
+    fn identical_files_share_one_pool_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        write_file(&a.join("file.txt"), "contents", 0o644);
+        write_file(&b.join("file.txt"), "contents", 0o644);
+        write_file(&b.join("other.txt"), "other", 0o644);
+
+        assert_eq!(dedup_snapshot(dir.path(), &a).unwrap(), 0);
+        assert_eq!(dedup_snapshot(dir.path(), &b).unwrap(), 1);
+        let ino = |path: PathBuf| fs::metadata(path).unwrap().ino();
+        assert_eq!(ino(a.join("file.txt")), ino(b.join("file.txt")));
+        assert_ne!(ino(b.join("file.txt")), ino(b.join("other.txt")));
+        assert_eq!(fs::metadata(a.join("file.txt")).unwrap().nlink(), 3);
+    }
+
+    #[test]
+    fn pool_entries_are_fanned_out_by_key_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = dir.path().join("a");
+        write_file(&snapshot.join("file.txt"), "contents", 0o644);
+        dedup_snapshot(dir.path(), &snapshot).unwrap();
+
+        let pooled = walk_files(&dir.path().join(POOL_DIR)).unwrap();
+        assert_eq!(pooled.len(), 1);
+        let name = pooled[0].file_name().unwrap().to_str().unwrap();
+        let fan_out = pooled[0].parent().unwrap();
+        assert_eq!(name.len(), 64);
+        assert_eq!(fan_out.file_name().unwrap().to_str().unwrap(), &name[..2]);
+        assert_eq!(fan_out.parent().unwrap(), dir.path().join(POOL_DIR));
+    }
+
+    #[test]
+    fn chmod_in_a_later_snapshot_leaves_the_earlier_one_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        write_file(&a.join("file.txt"), "contents", 0o644);
+        write_file(&b.join("file.txt"), "contents", 0o600);
+
+        dedup_snapshot(dir.path(), &a).unwrap();
+        assert_eq!(dedup_snapshot(dir.path(), &b).unwrap(), 0);
+        let mode = |path: PathBuf| fs::metadata(path).unwrap().mode() & 0o777;
+        assert_eq!(mode(a.join("file.txt")), 0o644);
+        assert_eq!(mode(b.join("file.txt")), 0o600);
+    }
+
+    #[test]
+    fn pool_entries_without_links_are_garbage() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(find_pool_garbage(dir.path()).unwrap().is_empty());
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        write_file(&a.join("file.txt"), "contents", 0o644);
+        write_file(&b.join("other.txt"), "other", 0o644);
+        dedup_snapshot(dir.path(), &a).unwrap();
+        dedup_snapshot(dir.path(), &b).unwrap();
+        assert!(find_pool_garbage(dir.path()).unwrap().is_empty());
+
+        fs::remove_dir_all(&a).unwrap();
+        let garbage = find_pool_garbage(dir.path()).unwrap();
+        assert_eq!(garbage.len(), 1);
+        assert_eq!(fs::read_to_string(&garbage[0]).unwrap(), "contents");
+    }
+}
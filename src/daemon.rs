@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Context, Result};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use tracing::{error, info};
+use crate::archive::archive_locked;
+use crate::config::{Config, Schedule};
+use crate::util::{parse_duration, LazyTempDir};
+
+/// Archives the target of `schedule`, or every target, as `archive` does. Stops at the first failing target.
+fn run_schedule(config: &Config, schedule: &Schedule) -> Result<()> {
+    // a temp dir per run, the daemon would otherwise collect exclude files in it
+    let temp_dir = LazyTempDir::new(config.temp_dir.clone());
+    let config = config.for_schedule(schedule).with_inline_excludes(&temp_dir)?;
+    for (target, config) in config.target_configs(schedule.target.as_deref())? {
+        let report = archive_locked(&config.effective_excludes(&temp_dir)?)?;
+        match target.is_empty() {
+            true => info!("schedule {}: {report}", schedule.name),
+            false => info!("schedule {}, target {target}: {report}", schedule.name),
+        }
+    }
+    Ok(())
+}
+
+/// Runs every `[[schedules]]` entry on its interval until SIGTERM/SIGINT. Schedules fire once on startup.
+pub fn run_daemon(config: &Config) -> Result<()> {
+    if config.schedules.is_empty() {
+        return Err(anyhow!("no [[schedules]] configured"));
+    }
+    let mut next_runs = Vec::new();
+    for schedule in &config.schedules {
+        let every = parse_duration(&schedule.every).context(format!("schedule {}", schedule.name))?;
+        config.target_configs(schedule.target.as_deref()).context(format!("schedule {}", schedule.name))?;
+        next_runs.push((schedule, every, Instant::now()));
+    }
+
+    let terminate = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGTERM, Arc::clone(&terminate)).context("registering SIGTERM handler")?;
+    signal_hook::flag::register(SIGINT, Arc::clone(&terminate)).context("registering SIGINT handler")?;
+
+    info!("daemon started with {} schedules", next_runs.len());
+    while !terminate.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        for (schedule, every, next_run) in next_runs.iter_mut() {
+            if *next_run > now {
+                continue;
+            }
+            *next_run = now + *every;
+            info!("schedule {} fired", schedule.name);
+            if let Err(e) = run_schedule(config, schedule) {
+                error!("schedule {} failed: {e:#}", schedule.name);
+            }
+            if terminate.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+        let until_next = next_runs.iter()
+            .map(|(_, _, next_run)| next_run.saturating_duration_since(Instant::now()))
+            .min()
+            .unwrap_or_default();
+        thread::sleep(until_next.min(Duration::from_secs(1)));
+    }
+    info!("terminating");
+    Ok(())
+}
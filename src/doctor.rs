@@ -0,0 +1,452 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset};
+use tracing::{info, warn};
+use crate::gc::{collect_garbage, split_sidecar_name};
+use crate::meta::read_meta;
+use crate::syncer_util::{sidecar_path, snapshot_file_name, timestamp_named_folders, ChangeList, SnapshotNaming, CHANGES_EXT, DIFF_EXT, DIFF_ZST_EXT, STAGING_PREFIX};
+use crate::util::Failures;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Info => write!(f, "INFO"),
+            Severity::Warning => write!(f, "WARN"),
+            Severity::Error => write!(f, "ERROR"),
+        }
+    }
+}
+
+/// Automatic repair that is safe to perform without asking.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fix {
+    /// Remove a snapshot folder that contains no files and was never finished
+    RemoveEmptyFolder(PathBuf),
+    /// Run `gc`
+    CollectGarbage,
+}
+
+#[derive(Debug)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+    pub suggestion: Option<String>,
+    pub fix: Option<Fix>,
+}
+
+impl Finding {
+    fn new<S: Into<String>>(severity: Severity, message: S) -> Self {
+        Finding { severity, message: message.into(), suggestion: None, fix: None }
+    }
+
+    fn suggest<S: Into<String>>(mut self, suggestion: S) -> Self {
+        self.suggestion = Some(suggestion.into());
+        self
+    }
+
+    fn with_fix(mut self, fix: Fix) -> Self {
+        self.fix = Some(fix);
+        self
+    }
+}
+
+fn contains_files(dir: &Path) -> Result<bool> {
+    for entry in fs::read_dir(dir).context(format!("unable to read {dir:?}"))? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() || contains_files(&entry.path())? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+pub fn diagnose(local_archive: &Path, naming: &SnapshotNaming) -> Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    let mut snapshots: Vec<(DateTime<FixedOffset>, String, PathBuf)> = Vec::new();
+    let mut sidecars: Vec<(DateTime<FixedOffset>, String, PathBuf)> = Vec::new();
+
+    for entry in fs::read_dir(local_archive).context("unable to read local archive")? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_name = match entry.file_name().to_str() {
+            Some(name) => name.to_owned(),
+            None => {
+                findings.push(Finding::new(Severity::Warning, format!("non-unicode name: {path:?}"))
+                    .suggest("rename it or move it out of the archive"));
+                continue
+            }
+        };
+        if entry.metadata()?.is_dir() {
+            if file_name.strip_prefix(STAGING_PREFIX).is_some_and(|name| naming.parse(name).is_some()) {
+                findings.push(Finding::new(Severity::Warning, format!("{path:?} is left over from an interrupted archive run"))
+                    .suggest("the next archive run resumes it, or run gc to remove it")
+                    .with_fix(Fix::CollectGarbage));
+                continue;
+            }
+            if file_name.starts_with('.') || naming.is_ignored(&file_name) || naming.strip_affixes(&file_name).is_none()
+                || naming.hierarchical {
+                // internal folder, ignore_dirs, belongs to another schedule or date folders, scanned below
+                continue;
+            }
+            match naming.parse(&file_name) {
+                Some(timestamp) => snapshots.push((timestamp, file_name, path)),
+                None => findings.push(Finding::new(Severity::Warning, format!("folder name does not parse as a timestamp: {path:?}"))
+                    .suggest("rename it to match date_format or move it out of the archive"))
+            }
+        } else if let Some((name, _)) = split_sidecar_name(&file_name) {
+            if let Some(timestamp) = naming.parse(name) {
+                sidecars.push((timestamp, name.to_owned(), path));
+            }
+        }
+    }
+    if naming.hierarchical {
+        snapshots = timestamp_named_folders(local_archive, naming)?.into_iter()
+            .map(|(timestamp, name)| {
+                let path = local_archive.join(&name);
+                (timestamp, name, path)
+            })
+            .collect();
+    }
+    snapshots.sort();
+    sidecars.sort();
+
+    if snapshots.is_empty() {
+        if sidecars.is_empty() {
+            findings.push(Finding::new(Severity::Info, "archive is empty"));
+        } else {
+            findings.push(Finding::new(Severity::Error, "change lists exist, but there are no snapshots")
+                .suggest("restore the snapshot folders from a backup or start a new archive"));
+        }
+        return Ok(findings);
+    }
+
+    for pair in snapshots.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            findings.push(Finding::new(Severity::Warning, format!("{:?} and {:?} have the same timestamp", pair[0].2, pair[1].2))
+                .suggest("keep one of them, the other one is picked arbitrarily as the latest"));
+        }
+    }
+
+    let oldest = snapshots[0].0;
+    for (i, (_, name, path)) in snapshots.iter().enumerate() {
+        let has_changes = sidecar_path(local_archive, name, CHANGES_EXT).exists();
+        if contains_files(path)? {
+            if !has_changes && i != 0 {
+                findings.push(Finding::new(Severity::Warning, format!("snapshot {path:?} has no change list, it may be incomplete"))
+                    .suggest("compare it against the working dir, remove it manually if it is broken"));
+            }
+        } else if i == 0 {
+            findings.push(Finding::new(Severity::Info, format!("oldest snapshot {path:?} is empty, it is the initial base")));
+        } else if has_changes {
+            findings.push(Finding::new(Severity::Info, format!("snapshot {path:?} has no files, working dir was empty")));
+        } else {
+            findings.push(Finding::new(Severity::Warning, format!("snapshot {path:?} has no files and was never finished"))
+                .suggest("remove it")
+                .with_fix(Fix::RemoveEmptyFolder(path.clone())));
+        }
+    }
+
+    let snapshot_names: HashSet<&str> = snapshots.iter().map(|(_, name, _)| snapshot_file_name(name)).collect();
+    for (timestamp, name, path) in &sidecars {
+        if *timestamp < oldest {
+            findings.push(Finding::new(Severity::Warning, format!("{path:?} is older than the oldest snapshot"))
+                .suggest("run gc")
+                .with_fix(Fix::CollectGarbage));
+        } else if !snapshot_names.contains(name.as_str()) {
+            findings.push(Finding::new(Severity::Info, format!("{path:?} belongs to a fast-forwarded snapshot")));
+        }
+    }
+
+    let latest_snapshot = snapshots[snapshots.len() - 1].0;
+    if let Some((timestamp, _, path)) = sidecars.last() {
+        if *timestamp > latest_snapshot {
+            findings.push(Finding::new(Severity::Error, format!("latest run recorded {path:?}, but its snapshot is missing"))
+                .suggest("run archive again, then remove the stale batch and change list files of the failed run"));
+        }
+    }
+
+    findings.sort_by_key(|f| std::cmp::Reverse(f.severity));
+    Ok(findings)
+}
+
+/// Checks that the snapshots form an unbroken chain of batch files: every snapshot but the first has a `.diff`,
+/// no two snapshots share a timestamp, batch files were written in timestamp order and recorded moves point at
+/// files that exist in their snapshot. Recorded parent links must point at older snapshots that still have their
+/// folder or batch file, unless they were pruned.
+/// With `keep_going` unreadable batch files are skipped and returned, instead of ending the check.
+pub fn verify_chain(local_archive: &Path, naming: &SnapshotNaming, keep_going: bool) -> Result<(Vec<Finding>, Failures)> {
+    let mut findings = Vec::new();
+    let mut failures = Vec::new();
+    let snapshots = timestamp_named_folders(local_archive, naming)?;
+    if snapshots.is_empty() {
+        findings.push(Finding::new(Severity::Info, "archive is empty"));
+        return Ok((findings, failures));
+    }
+
+    for pair in snapshots.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            findings.push(Finding::new(Severity::Error, format!("{} and {} have the same timestamp", pair[0].1, pair[1].1))
+                .suggest("keep one of them"));
+        }
+    }
+
+    let mut diffs: Vec<(DateTime<FixedOffset>, String, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(local_archive).context("unable to read local archive")? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        if let Some((name, DIFF_EXT | DIFF_ZST_EXT)) = file_name.to_str().and_then(split_sidecar_name) {
+            if let Some(timestamp) = naming.parse(name) {
+                diffs.push((timestamp, name.to_owned(), entry.path()));
+            }
+        }
+    }
+    diffs.sort();
+    let diff_names: HashSet<&str> = diffs.iter().map(|(_, name, _)| name.as_str()).collect();
+
+    for (_, name) in snapshots.iter().skip(1) {
+        if !diff_names.contains(snapshot_file_name(name)) {
+            findings.push(Finding::new(Severity::Error, format!("snapshot {name} has no batch file, the chain is broken before it"))
+                .suggest("replay across it is impossible, restore it only as a full snapshot"));
+        }
+    }
+
+    let oldest = snapshots[0].0;
+    for (timestamp, name) in &snapshots {
+        let parent = match read_meta(local_archive, name) {
+            Ok(meta) => match meta.and_then(|meta| meta.parent) {
+                Some(parent) => parent,
+                None => continue
+            },
+            Err(e) => {
+                findings.push(Finding::new(Severity::Warning, format!("{e:#}")));
+                continue;
+            }
+        };
+        match naming.parse(&parent) {
+            Some(parent_timestamp) if parent_timestamp >= *timestamp => {
+                findings.push(Finding::new(Severity::Error, format!("{name} records parent {parent}, which is not older than it")));
+            }
+            Some(parent_timestamp) if parent_timestamp >= oldest => {
+                if !local_archive.join(&parent).is_dir() && !diff_names.contains(snapshot_file_name(&parent)) {
+                    findings.push(Finding::new(Severity::Error, format!("{name} records parent {parent}, which has neither a folder nor a batch file"))
+                        .suggest("replay into it is impossible, restore it only as a full snapshot"));
+                }
+            }
+            // older than every snapshot left, pruned
+            Some(_) => {}
+            None => findings.push(Finding::new(Severity::Warning, format!("{name} records parent {parent:?}, which is not a snapshot name")))
+        }
+    }
+
+    let mut previous: Option<(&str, std::time::SystemTime)> = None;
+    for (_, name, path) in &diffs {
+        let modified = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(e) if keep_going => {
+                warn!("{path:?}: {e}");
+                failures.push((path.clone(), anyhow::Error::new(e)));
+                continue;
+            }
+            Err(e) => return Err(e).context(format!("reading {path:?}"))
+        };
+        if let Some((previous_name, previous_modified)) = previous {
+            if modified < previous_modified {
+                findings.push(Finding::new(Severity::Warning, format!("batch file of {name} was written before the one of {previous_name}, out of timestamp order"))
+                    .suggest("check whether the clock or time zone was changed between the runs"));
+            }
+        }
+        previous = Some((name, modified));
+    }
+
+    for (_, name) in &snapshots {
+        let changes_path = sidecar_path(local_archive, name, CHANGES_EXT);
+        let changes: ChangeList = match fs::read_to_string(&changes_path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(changes) => changes,
+                Err(e) => {
+                    findings.push(Finding::new(Severity::Warning, format!("{changes_path:?} doesn't parse: {e}")));
+                    continue;
+                }
+            },
+            Err(_) => continue
+        };
+        for (from, to) in &changes.moved {
+            if !local_archive.join(name).join(to).exists() {
+                findings.push(Finding::new(Severity::Warning, format!("{name} records a move of {:?} to {to:?}, which is not in the snapshot", from.path())));
+            }
+        }
+    }
+
+    findings.sort_by_key(|f| std::cmp::Reverse(f.severity));
+    Ok((findings, failures))
+}
+
+/// Performs the automatic repairs of the given findings. Never touches snapshots that contain files. With
+/// `run_in_progress`, another run holds the archive lock: empty snapshot folders and staging folders may be its
+/// work in progress and are left alone.
+pub fn repair(findings: &[Finding], local_archive: &Path, naming: &SnapshotNaming, run_in_progress: bool) -> Result<()> {
+    let mut gc_needed = false;
+    for fix in findings.iter().filter_map(|f| f.fix.as_ref()) {
+        match fix {
+            Fix::RemoveEmptyFolder(path) => {
+                if run_in_progress || contains_files(path)? {
+                    continue;
+                }
+                info!("removing empty folder {path:?}");
+                fs::remove_dir_all(path).context(format!("removing {path:?}"))?;
+            }
+            Fix::CollectGarbage => {
+                gc_needed = true;
+            }
+        }
+    }
+    if gc_needed {
+        collect_garbage(local_archive, naming, !run_in_progress)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syncer_util::staging_name;
+    use crate::test_support::{naming, snapshot_name};
+
+    fn finding<'a>(findings: &'a [Finding], message: &str) -> &'a Finding {
+        findings.iter().find(|f| f.message.contains(message)).unwrap_or_else(|| panic!("no {message:?} in {findings:?}"))
+    }
+
+    /// Snapshot folder `days` old with one file and a change list.
+    fn snapshot(archive: &Path, naming: &SnapshotNaming, days: i64) -> PathBuf {
+        let name = snapshot_name(naming, days);
+        fs::create_dir(archive.join(&name)).unwrap();
+        fs::write(archive.join(&name).join("file.txt"), "").unwrap();
+        fs::write(sidecar_path(archive, &name, CHANGES_EXT), "").unwrap();
+        archive.join(name)
+    }
+
+    #[test]
+    fn healthy_archive_has_no_warnings() {
+        let dir = tempfile::tempdir().unwrap();
+        let naming = naming();
+        fs::create_dir(dir.path().join(snapshot_name(&naming, 3))).unwrap();
+        snapshot(dir.path(), &naming, 2);
+        snapshot(dir.path(), &naming, 1);
+        let findings = diagnose(dir.path(), &naming).unwrap();
+        assert!(findings.iter().all(|f| f.severity == Severity::Info), "{findings:?}");
+        assert_eq!(diagnose(tempfile::tempdir().unwrap().path(), &naming).unwrap()[0].message, "archive is empty");
+    }
+
+    #[test]
+    fn unparsable_folder_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let naming = naming();
+        snapshot(dir.path(), &naming, 1);
+        fs::create_dir(dir.path().join("Jan99_2024")).unwrap();
+        let findings = diagnose(dir.path(), &naming).unwrap();
+        let found = finding(&findings, "does not parse as a timestamp");
+        assert_eq!(found.severity, Severity::Warning);
+        assert_eq!(found.fix, None);
+    }
+
+    #[test]
+    fn orphaned_sidecars_are_collected() {
+        let dir = tempfile::tempdir().unwrap();
+        let naming = naming();
+        snapshot(dir.path(), &naming, 1);
+        let orphan = sidecar_path(dir.path(), &snapshot_name(&naming, 5), DIFF_EXT);
+        fs::write(&orphan, "").unwrap();
+        let findings = diagnose(dir.path(), &naming).unwrap();
+        assert_eq!(finding(&findings, "older than the oldest snapshot").fix, Some(Fix::CollectGarbage));
+
+        repair(&findings, dir.path(), &naming, false).unwrap();
+        assert!(!orphan.exists());
+    }
+
+    #[test]
+    fn unfinished_empty_snapshot_is_removed_but_never_one_with_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let naming = naming();
+        snapshot(dir.path(), &naming, 3);
+        let with_files = dir.path().join(snapshot_name(&naming, 2));
+        fs::create_dir(&with_files).unwrap();
+        fs::write(with_files.join("file.txt"), "").unwrap();
+        let empty = dir.path().join(snapshot_name(&naming, 1));
+        fs::create_dir(&empty).unwrap();
+        let findings = diagnose(dir.path(), &naming).unwrap();
+        assert_eq!(finding(&findings, "never finished").fix, Some(Fix::RemoveEmptyFolder(empty.clone())));
+        assert_eq!(finding(&findings, "has no change list").fix, None);
+
+        // a folder that got files since the diagnosis is not empty anymore
+        let stale = [Finding::new(Severity::Warning, "stale").with_fix(Fix::RemoveEmptyFolder(with_files.clone()))];
+        repair(&stale, dir.path(), &naming, false).unwrap();
+        repair(&findings, dir.path(), &naming, false).unwrap();
+        assert!(!empty.exists());
+        assert!(with_files.join("file.txt").exists());
+    }
+
+    #[test]
+    fn same_second_snapshots_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let naming = naming();
+        let now = chrono::Local::now() - chrono::Duration::days(1);
+        for offset in [0, 3600] {
+            let name = naming.format(&now.with_timezone(&FixedOffset::east_opt(offset).unwrap()));
+            fs::create_dir(dir.path().join(&name)).unwrap();
+            fs::write(dir.path().join(&name).join("file.txt"), "").unwrap();
+        }
+        let findings = diagnose(dir.path(), &naming).unwrap();
+        assert_eq!(finding(&findings, "have the same timestamp").severity, Severity::Warning);
+    }
+
+    #[test]
+    fn missing_latest_snapshot_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let naming = naming();
+        snapshot(dir.path(), &naming, 2);
+        fs::write(sidecar_path(dir.path(), &snapshot_name(&naming, 1), DIFF_EXT), "").unwrap();
+        let findings = diagnose(dir.path(), &naming).unwrap();
+        assert_eq!(findings[0].severity, Severity::Error);
+        assert!(findings[0].message.contains("its snapshot is missing"), "{findings:?}");
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(sidecar_path(dir.path(), &snapshot_name(&naming, 1), CHANGES_EXT), "").unwrap();
+        let findings = diagnose(dir.path(), &naming).unwrap();
+        assert_eq!(finding(&findings, "there are no snapshots").severity, Severity::Error);
+    }
+
+    #[test]
+    fn repair_leaves_the_work_of_a_running_archive_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path();
+        let naming = naming();
+        fs::create_dir(archive.join(snapshot_name(&naming, 2))).unwrap();
+        fs::write(archive.join(snapshot_name(&naming, 2)).join("file.txt"), "").unwrap();
+        let empty = archive.join(snapshot_name(&naming, 1));
+        fs::create_dir(&empty).unwrap();
+        let staging = archive.join(staging_name(&snapshot_name(&naming, 0)));
+        fs::create_dir(&staging).unwrap();
+        let findings = [
+            Finding::new(Severity::Warning, "empty").with_fix(Fix::RemoveEmptyFolder(empty.clone())),
+            Finding::new(Severity::Warning, "staging").with_fix(Fix::CollectGarbage),
+        ];
+
+        repair(&findings, archive, &naming, true).unwrap();
+        assert!(empty.is_dir());
+        assert!(staging.is_dir());
+
+        repair(&findings, archive, &naming, false).unwrap();
+        assert!(!empty.exists());
+        assert!(!staging.exists());
+    }
+}
@@ -0,0 +1,39 @@
+use tracing::info;
+use crate::archive::ArchiveOutcome;
+use crate::syncer_util::ChangeList;
+
+/// Steps of an archive run, in the order they happen. A run without changes only sees `Started` and `Finished`.
+#[derive(Debug, Clone, Copy)]
+pub enum ArchiveEvent<'a> {
+    Started,
+    /// The batch file of `snapshot` was written
+    DetectedDiff { snapshot: &'a str, changes: &'a ChangeList },
+    /// `snapshot` was created from `base`, by renaming it when fast-forwarding
+    CopiedBase { snapshot: &'a str, base: &'a str, fast_forward: bool },
+    AppliedDiff { snapshot: &'a str },
+    /// The change list and the other sidecars were written, `snapshot` is complete
+    WroteChanges { snapshot: &'a str },
+    Finished { outcome: ArchiveOutcome, snapshot: Option<&'a str> },
+}
+
+/// Receives the events of archive runs, e.g. to show progress in a front-end.
+pub trait EventSink {
+    fn event(&self, event: &ArchiveEvent);
+}
+
+/// Logs each step as an info event with a `phase` field, the sink the command line uses.
+pub struct LoggingSink;
+
+impl EventSink for LoggingSink {
+    fn event(&self, event: &ArchiveEvent) {
+        match *event {
+            ArchiveEvent::Started => info!(phase = "started"),
+            ArchiveEvent::DetectedDiff { snapshot, changes } => info!(phase = "detected-diff", snapshot = %snapshot,
+                changed = changes.changed.len(), deleted = changes.deleted.len(), moved = changes.moved.len()),
+            ArchiveEvent::CopiedBase { snapshot, base, fast_forward } => info!(phase = "copied-base", snapshot = %snapshot, base = %base, fast_forward = fast_forward),
+            ArchiveEvent::AppliedDiff { snapshot } => info!(phase = "applied-diff", snapshot = %snapshot),
+            ArchiveEvent::WroteChanges { snapshot } => info!(phase = "wrote-changes", snapshot = %snapshot),
+            ArchiveEvent::Finished { outcome, snapshot } => info!(phase = "finished", outcome = ?outcome, snapshot = snapshot.unwrap_or_default()),
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use anyhow::{Context, Result};
+
+/// Writes snapshot folder `dir` as a tar stream to `out`. Entries are sorted by path so that the same snapshot
+/// always gives the same stream, symlinks are stored as links. Permissions, uid, gid and mtime are stored as they
+/// are on disk, so a root owned archive exports root owned entries.
+pub fn export_snapshot<W: Write>(dir: &Path, out: W) -> Result<()> {
+    let mut builder = tar::Builder::new(out);
+    builder.follow_symlinks(false);
+    append_sorted(&mut builder, dir, Path::new(""))?;
+    builder.into_inner().context("finishing tar stream")?.flush()?;
+    Ok(())
+}
+
+fn append_sorted<W: Write>(builder: &mut tar::Builder<W>, dir: &Path, relative: &Path) -> Result<()> {
+    let mut entries = fs::read_dir(dir).context(format!("reading {dir:?}"))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = relative.join(entry.file_name());
+        builder.append_path_with_name(&path, &name).context(format!("adding {path:?}"))?;
+        if entry.file_type()?.is_dir() {
+            append_sorted(builder, &path, &name)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+    use std::path::PathBuf;
+
+    #[test]
+    fn exported_snapshot_unpacks_to_the_same_tree() {
+        let snapshot = tempfile::tempdir().unwrap();
+        let dir = snapshot.path();
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/script.sh"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(dir.join("sub/script.sh"), fs::Permissions::from_mode(0o750)).unwrap();
+        fs::write(dir.join("b.txt"), "b").unwrap();
+        fs::set_permissions(dir.join("b.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+        symlink("sub/script.sh", dir.join("a-link")).unwrap();
+
+        let mut stream = Vec::new();
+        export_snapshot(dir, &mut stream).unwrap();
+
+        let mut archive = tar::Archive::new(&stream[..]);
+        let names: Vec<_> = archive.entries().unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect();
+        assert_eq!(names, ["a-link", "b.txt", "sub", "sub/script.sh"].map(PathBuf::from));
+
+        let unpacked = tempfile::tempdir().unwrap();
+        let mut archive = tar::Archive::new(&stream[..]);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_mtime(true);
+        archive.unpack(unpacked.path()).unwrap();
+        for name in &names {
+            let original = fs::symlink_metadata(dir.join(name)).unwrap();
+            let copy = fs::symlink_metadata(unpacked.path().join(name)).unwrap();
+            assert_eq!(copy.file_type(), original.file_type(), "{name:?}");
+            if !original.file_type().is_symlink() {
+                assert_eq!(copy.mode(), original.mode(), "{name:?}");
+                assert_eq!(copy.mtime(), original.mtime(), "{name:?}");
+            }
+        }
+        assert_eq!(fs::read_link(unpacked.path().join("a-link")).unwrap(), Path::new("sub/script.sh"));
+        assert_eq!(fs::read_to_string(unpacked.path().join("sub/script.sh")).unwrap(), "#!/bin/sh\n");
+        assert_eq!(fs::read_to_string(unpacked.path().join("b.txt")).unwrap(), "b");
+    }
+
+    #[test]
+    fn export_is_reproducible() {
+        let snapshot = tempfile::tempdir().unwrap();
+        for name in ["c", "a", "b"] {
+            fs::write(snapshot.path().join(name), name).unwrap();
+        }
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        export_snapshot(snapshot.path(), &mut first).unwrap();
+        export_snapshot(snapshot.path(), &mut second).unwrap();
+        assert_eq!(first, second);
+    }
+}
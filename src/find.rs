@@ -0,0 +1,48 @@
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset};
+use crate::syncer_util::{timestamp_named_folders, SnapshotNaming};
+use crate::util::hash_file;
+
+/// One version of a file and the consecutive snapshots that contain it.
+#[derive(Debug)]
+pub struct FileVersion {
+    pub first_seen: String,
+    pub last_seen: String,
+    pub size: u64,
+    pub hash: blake3::Hash,
+}
+
+/// Versions of `relative` in the snapshots, oldest first. Snapshots are scanned newest to oldest, those taken
+/// before `since` are not scanned. A version that comes back after the file was changed or missing is listed again.
+pub fn find_file_versions(local_archive: &Path, naming: &SnapshotNaming, relative: &Path, since: Option<DateTime<FixedOffset>>) -> Result<Vec<FileVersion>> {
+    if relative.is_absolute() {
+        return Err(anyhow!("{relative:?} must be relative to the working dir"));
+    }
+    let mut versions: Vec<FileVersion> = Vec::new();
+    // whether the version at the end of `versions` was seen in the previously scanned (newer) snapshot
+    let mut continues = false;
+    for (timestamp, name) in timestamp_named_folders(local_archive, naming)?.into_iter().rev() {
+        if since.is_some_and(|since| timestamp < since) {
+            break;
+        }
+        let path = local_archive.join(&name).join(relative);
+        if !path.is_file() {
+            continues = false;
+            continue;
+        }
+        let hash = hash_file(&path)?;
+        match versions.last_mut() {
+            Some(version) if continues && version.hash == hash => version.first_seen = name,
+            _ => versions.push(FileVersion {
+                first_seen: name.clone(),
+                last_seen: name,
+                size: path.metadata()?.len(),
+                hash,
+            })
+        }
+        continues = true;
+    }
+    versions.reverse();
+    Ok(versions)
+}
@@ -0,0 +1,137 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset};
+use tracing::info;
+use crate::syncer_util::{timestamp_named_folders, SnapshotNaming, SIDECAR_EXTS, STAGING_PREFIX};
+use crate::util::{for_each_path, Failures};
+
+/// Returns the snapshot name and extension if `file_name` looks like `<snapshot>.diff`, `<snapshot>.diff.zst`,
+/// `<snapshot>.changes`, `<snapshot>.meta.json`, `<snapshot>.pin`, `<snapshot>.filelist`, `<snapshot>.rsynclog`
+/// `<snapshot>.incomplete` or `<snapshot>.mirrored`.
+pub fn split_sidecar_name(file_name: &str) -> Option<(&str, &str)> {
+    for ext in SIDECAR_EXTS {
+        if let Some(name) = file_name.strip_suffix(ext).and_then(|n| n.strip_suffix('.')) {
+            return Some((name, ext));
+        }
+    }
+    None
+}
+
+/// Lists change lists and batch files older than the oldest snapshot, staging folders of interrupted runs and
+/// unused dedup pool entries. Staging folders are left out without `include_staging`, while a run holds the
+/// archive lock its staging folder is in use.
+/// Sidecars of fast-forwarded snapshots in between are kept, they are still part of the history.
+/// Nothing is garbage if there are no snapshots at all.
+pub fn find_garbage(local_archive: &Path, naming: &SnapshotNaming, include_staging: bool) -> Result<Vec<PathBuf>> {
+    let mut oldest: Option<DateTime<FixedOffset>> = None;
+    let mut sidecars = Vec::new();
+    let mut staging = Vec::new();
+    for entry in fs::read_dir(local_archive).context("unable to read local archive")? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = match file_name.to_str() {
+            Some(name) => name,
+            None => continue
+        };
+        if entry.metadata()?.is_dir() {
+            if file_name.strip_prefix(STAGING_PREFIX).is_some_and(|name| naming.parse(name).is_some()) {
+                if include_staging {
+                    staging.push(entry.path());
+                }
+            } else if let Some(timestamp) = naming.parse(file_name) {
+                oldest = Some(oldest.map_or(timestamp, |o| o.min(timestamp)));
+            }
+        } else if let Some((name, _)) = split_sidecar_name(file_name) {
+            if let Some(timestamp) = naming.parse(name) {
+                sidecars.push((timestamp, entry.path()));
+            }
+        }
+    }
+
+    if naming.hierarchical {
+        // snapshots are in date folders, not seen above
+        oldest = timestamp_named_folders(local_archive, naming)?.first().map(|(timestamp, _)| *timestamp);
+    }
+    let oldest = match oldest {
+        Some(oldest) => oldest,
+        None => return Ok(vec![])
+    };
+    #[cfg(feature = "cas")]
+    let mut garbage = crate::cas::find_pool_garbage(local_archive)?;
+    #[cfg(not(feature = "cas"))]
+    let mut garbage = Vec::new();
+    garbage.extend(staging);
+    for (timestamp, path) in sidecars {
+        if timestamp < oldest {
+            garbage.push(path);
+        }
+    }
+    Ok(garbage)
+}
+
+/// Returns the paths that couldn't be removed when `keep_going` is set.
+pub fn remove_garbage(garbage: &[PathBuf], keep_going: bool) -> Result<Failures> {
+    for_each_path(garbage, keep_going, |path| {
+        info!("removing {path:?}");
+        if path.is_dir() {
+            fs::remove_dir_all(path).context(format!("removing {path:?}"))
+        } else {
+            fs::remove_file(path).context(format!("removing {path:?}"))
+        }
+    })
+}
+
+/// `find_garbage` and `remove_garbage` in one go.
+pub fn collect_garbage(local_archive: &Path, naming: &SnapshotNaming, include_staging: bool) -> Result<Vec<PathBuf>> {
+    let garbage = find_garbage(local_archive, naming, include_staging)?;
+    remove_garbage(&garbage, false)?;
+    Ok(garbage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syncer_util::{sidecar_path, staging_name, CHANGES_EXT, DIFF_EXT, DIFF_ZST_EXT};
+    use crate::test_support::{naming, snapshot_name};
+
+    #[test]
+    fn staging_folders_are_kept_while_a_run_is_in_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path();
+        let naming = naming();
+        let (old, latest, new) = (snapshot_name(&naming, 3), snapshot_name(&naming, 1), snapshot_name(&naming, 0));
+        fs::create_dir(archive.join(&latest)).unwrap();
+        fs::write(sidecar_path(archive, &old, CHANGES_EXT), "{}").unwrap();
+        fs::write(sidecar_path(archive, &latest, CHANGES_EXT), "{}").unwrap();
+        fs::create_dir(archive.join(staging_name(&new))).unwrap();
+
+        let mut garbage = find_garbage(archive, &naming, true).unwrap();
+        garbage.sort();
+        let mut expected = vec![sidecar_path(archive, &old, CHANGES_EXT), archive.join(staging_name(&new))];
+        expected.sort();
+        assert_eq!(garbage, expected);
+        assert_eq!(find_garbage(archive, &naming, false).unwrap(), [sidecar_path(archive, &old, CHANGES_EXT)]);
+    }
+
+    #[test]
+    fn compressed_batch_files_are_sidecars() {
+        let naming = naming();
+        let old = snapshot_name(&naming, 3);
+        assert_eq!(split_sidecar_name(&format!("{old}.diff.zst")), Some((old.as_str(), DIFF_ZST_EXT)));
+        assert_eq!(split_sidecar_name(&format!("{old}.diff")), Some((old.as_str(), DIFF_EXT)));
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join(snapshot_name(&naming, 1))).unwrap();
+        fs::write(sidecar_path(dir.path(), &old, DIFF_ZST_EXT), "").unwrap();
+        assert_eq!(find_garbage(dir.path(), &naming, true).unwrap(), [sidecar_path(dir.path(), &old, DIFF_ZST_EXT)]);
+    }
+
+    #[test]
+    fn nothing_is_garbage_without_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let naming = naming();
+        fs::write(sidecar_path(dir.path(), &snapshot_name(&naming, 3), CHANGES_EXT), "{}").unwrap();
+        assert!(find_garbage(dir.path(), &naming, true).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use crate::util::find_executable_in_path;
+use serde::{Deserialize, Serialize};
+use subprocess::{Exec, Redirection};
+use tracing::warn;
+
+/// `[git]` section, for working dirs that are git clones.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GitConfig {
+    /// Record the commit checked out in the working dir in the `.meta.json` sidecar
+    #[serde(default)]
+    pub record_head: bool,
+    /// Refuse to archive a working dir with uncommitted changes or a detached HEAD, only warn otherwise
+    #[serde(default)]
+    pub require_clean: bool,
+}
+
+impl GitConfig {
+    fn is_enabled(&self) -> bool {
+        self.record_head || self.require_clean
+    }
+}
+
+/// What `git` reports about a working dir.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitState {
+    pub head: String,
+    pub dirty: bool,
+    pub detached: bool,
+}
+
+/// Runs git with `args` in `working_dir`, None if it fails, e.g. outside of a repository.
+fn run_git(working_dir: &Path, args: &[&str]) -> Result<Option<String>> {
+    let git_path = find_executable_in_path("git").context("failed to find git in PATH")?;
+    let capture = Exec::cmd(git_path)
+        .arg("-C")
+        .arg(working_dir)
+        .args(args)
+        .stdout(Redirection::Pipe)
+        .stderr(Redirection::Pipe)
+        .capture()
+        .context(format!("running git {}", args.join(" ")))?;
+    if !capture.exit_status.success() {
+        return Ok(None);
+    }
+    Ok(Some(capture.stdout_str().trim().to_owned()))
+}
+
+/// State of the git clone in `working_dir`, None if it isn't one.
+pub fn git_state(working_dir: &Path) -> Result<Option<GitState>> {
+    let head = match run_git(working_dir, &["rev-parse", "HEAD"])? {
+        Some(head) => head,
+        None => return Ok(None)
+    };
+    let status = run_git(working_dir, &["status", "--porcelain"])?.ok_or(anyhow!("git status failed in {working_dir:?}"))?;
+    let detached = run_git(working_dir, &["symbolic-ref", "-q", "HEAD"])?.is_none();
+    Ok(Some(GitState { head, dirty: !status.is_empty(), detached }))
+}
+
+/// Checks the working dir according to `config`, returns the commit to record.
+pub fn check_working_dir(config: &GitConfig, working_dir: &Path) -> Result<Option<String>> {
+    if !config.is_enabled() {
+        return Ok(None);
+    }
+    let state = match git_state(working_dir)? {
+        Some(state) => state,
+        None => {
+            warn!("[git] is configured, but {working_dir:?} is not a git repository");
+            return Ok(None);
+        }
+    };
+    let problem = match (state.dirty, state.detached) {
+        (true, _) => Some("has uncommitted changes"),
+        (false, true) => Some("has a detached HEAD"),
+        (false, false) => None,
+    };
+    if let Some(problem) = problem {
+        if config.require_clean {
+            return Err(anyhow!("{working_dir:?} {problem}, not archiving (require_clean = true)"));
+        }
+        warn!("{working_dir:?} {problem}, the snapshot won't match commit {}", state.head);
+    }
+    Ok(config.record_head.then_some(state.head))
+}
@@ -0,0 +1,34 @@
+//! Snapshot archiving of a working dir with rsync batch files. The `vhbarchsync` binary is a command line
+//! front-end, other front-ends can drive `archive::archive_with_events` with their own `events::EventSink`.
+pub mod util;
+pub mod syncer_util;
+pub mod archive;
+pub mod backend;
+pub mod backpressure;
+pub mod config;
+pub mod doctor;
+pub mod events;
+pub mod export;
+pub mod find;
+pub mod gc;
+pub mod git;
+pub mod lock;
+pub mod manifest;
+pub mod meta;
+pub mod metrics;
+pub mod migrate;
+pub mod mirror;
+pub mod redact;
+pub mod report;
+pub mod restore;
+pub mod retention;
+pub mod selftest;
+pub mod state;
+#[cfg(feature = "cas")]
+pub mod cas;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "daemon")]
+pub mod daemon;
+#[cfg(test)]
+mod test_support;
@@ -0,0 +1,88 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use anyhow::{anyhow, Context, Result};
+use tracing::warn;
+
+const LOCK_FILE: &str = ".vhbarchsync.lock";
+
+/// Lock file in the archive folder, prevents overlapping runs. Removed on drop.
+#[derive(Debug)]
+pub struct ArchiveLock {
+    path: PathBuf,
+}
+
+#[cfg(target_os = "linux")]
+fn is_stale(path: &Path) -> bool {
+    match fs::read_to_string(path) {
+        Ok(pid) => !Path::new("/proc").join(pid.trim()).exists(),
+        Err(_) => false
+    }
+}
+#[cfg(not(target_os = "linux"))]
+fn is_stale(_path: &Path) -> bool {
+    false
+}
+
+impl ArchiveLock {
+    pub fn acquire(local_archive: &Path) -> Result<Self> {
+        Self::try_acquire(local_archive)?.ok_or_else(|| {
+            anyhow!("{:?} exists, another run is in progress (remove the file if it is not)", local_archive.join(LOCK_FILE))
+        })
+    }
+
+    /// Like `acquire`, but None instead of an error while another run holds the lock.
+    pub fn try_acquire(local_archive: &Path) -> Result<Option<Self>> {
+        let path = local_archive.join(LOCK_FILE);
+        if is_stale(&path) {
+            warn!("removing stale lock {path:?}");
+            fs::remove_file(&path).context("removing stale lock")?;
+        }
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                write!(file, "{}", process::id()).context("writing lock file")?;
+                Ok(Some(ArchiveLock { path }))
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => Ok(None),
+            Err(e) => Err(e).context(format!("creating {path:?}"))
+        }
+    }
+}
+
+impl Drop for ArchiveLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("unable to remove lock {:?}: {e}", self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_run_is_locked_out_until_the_first_drops_its_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = ArchiveLock::acquire(dir.path()).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join(LOCK_FILE)).unwrap(), process::id().to_string());
+        assert!(ArchiveLock::acquire(dir.path()).is_err());
+        assert!(ArchiveLock::try_acquire(dir.path()).unwrap().is_none());
+
+        drop(lock);
+        assert!(!dir.path().join(LOCK_FILE).exists());
+        assert!(ArchiveLock::try_acquire(dir.path()).unwrap().is_some());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn lock_of_a_dead_process_is_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        // above the kernel's pid limit, never a running process
+        fs::write(dir.path().join(LOCK_FILE), u32::MAX.to_string()).unwrap();
+        let _lock = ArchiveLock::acquire(dir.path()).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join(LOCK_FILE)).unwrap(), process::id().to_string());
+    }
+}
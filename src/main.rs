@@ -1,66 +1,900 @@
-mod util;
-mod syncer_util;
-mod archive;
-
-use anyhow::{Context, Result};
-use clap::Parser;
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use clap::{Parser, Subcommand, ValueEnum};
+use comfy_table::Color;
+use glob::Pattern;
 use path_clean::PathClean;
-use serde::Deserialize;
 use std::fs;
-use std::path::PathBuf;
-use toml;
-use tracing::{Level};
+use std::io::{BufWriter, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use tracing::{debug, info, info_span, warn, Level};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::FmtSubscriber;
-use crate::archive::archive_local;
-use crate::util::remove_trailing_slash;
+use vhbarchsync::archive::{apply_local, archive_locked, diff_against_latest, estimate_transfer, extract_local, latest_matching_merkle_root, verify_retained, ArchiveOutcome, ArchiveReport};
+use vhbarchsync::config::{Config, ConfigFormat, SnapshotStrategy};
+use vhbarchsync::doctor::{diagnose, repair, verify_chain, Finding, Severity};
+use vhbarchsync::export::export_snapshot;
+use vhbarchsync::find::find_file_versions;
+use vhbarchsync::gc::{find_garbage, remove_garbage};
+use vhbarchsync::lock::ArchiveLock;
+use vhbarchsync::meta::{read_meta, sanitize_note, SnapshotMeta};
+use vhbarchsync::migrate::{migrate_snapshots, plan_migration};
+use vhbarchsync::redact::{redact, register_secrets, RedactingMakeWriter};
+use vhbarchsync::report::{change_rows, human_size, human_timestamp, read_change_list, Field, Output};
+use vhbarchsync::restore::{replay_snapshots, OnConflict, restore_snapshot, verify_restore};
+use vhbarchsync::retention::{find_prunable, is_pinned, pin_snapshot, remove_snapshots, retention_plan};
+use vhbarchsync::syncer_util::{check_folder_names, parse_rsync_debug_flags, timestamp_named_folders, FolderName, RsyncDebugFlags};
+use vhbarchsync::util::{confirm, is_empty_dir, parse_duration, Failures, LazyTempDir};
 
-#[derive(Deserialize)]
-struct Config {
-    #[serde(default = "default_date_format")]
-    date_format: String,
-    local_working_dir: PathBuf,
-    local_archive: PathBuf,
-    exclude: PathBuf
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    action: Action,
+    /// Don't ask before deleting anything
+    #[arg(short = 'y', long, global = true)]
+    assume_yes: bool,
+    /// Print tab separated values without colors or table borders from list, diff and stats
+    #[arg(long, global = true)]
+    plain: bool,
+    /// Format of the log output, archive runs emit a `phase` event at each step
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output_format: OutputFormat,
+    /// Pass rsync's own debug flags to the diff and apply runs, e.g. FILTER,DEL2. Their output is logged at trace level
+    #[arg(long, global = true, value_name = "SPEC", value_parser = parse_rsync_debug_flags)]
+    trace_rsync: Option<RsyncDebugFlags>,
+    /// Parser for the config file, by default picked by its extension: .json or .toml
+    #[arg(long, global = true, value_enum)]
+    config_format: Option<ConfigFormat>,
+    /// Let verify-chain, gc and prune continue past snapshots or files they fail on, and list the failures at the end
+    #[arg(long, global = true)]
+    keep_going: bool,
+    /// Folder for temporary files when the system temp dir is not writable, overrides temp_dir
+    #[arg(long, global = true)]
+    tmpdir: Option<PathBuf>,
+    /// Print the config as JSON to stderr once it is loaded, with defaults, includes, the global options and the
+    /// options of `archive` applied and credentials masked, instead of logging it at debug level
+    #[arg(long, global = true)]
+    print_effective_config: bool,
 }
 
-fn default_date_format() -> String {
-    "%b%d_%Y_%H%M%S%z".to_owned()
+/// Global options applied to every loaded config.
+struct ConfigOverrides {
+    format: Option<ConfigFormat>,
+    trace_rsync: Option<RsyncDebugFlags>,
+    tmpdir: Option<PathBuf>,
+    print_effective_config: bool,
 }
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    action: String,
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    /// Same as ndjson
+    Json,
+    /// One JSON object per line and event
+    Ndjson,
+}
+
+/// Options of `archive`
+#[derive(clap::Args, Debug)]
+struct ArchiveArgs {
     config: String,
+    /// Archive only this target
+    #[arg(long)]
+    target: Option<String>,
+    /// Write a plain text `<snapshot>.filelist` next to the change list, see `write_file_list`
+    #[arg(long)]
+    write_file_list: bool,
+    /// Use this exclude file instead of the configured `exclude` files and `exclude_inline` patterns, can be
+    /// repeated. Target exclude files still apply, --exclude-add patterns are appended after it
+    #[arg(long, conflicts_with = "no_exclude")]
+    exclude_file: Vec<PathBuf>,
+    /// Exclude nothing, ignoring the configured and target exclude files and `exclude_inline`. --exclude-add
+    /// patterns still apply
+    #[arg(long)]
+    no_exclude: bool,
+    /// Extra exclude pattern for this run, appended after all exclude files, can be repeated
+    #[arg(long)]
+    exclude_add: Vec<String>,
+    /// Archive only this many levels deep, overrides max_depth
+    #[arg(long)]
+    limit_depth: Option<usize>,
+    /// Compare only the paths listed in this file, `-` reads the list from stdin, overrides files_from
+    #[arg(long)]
+    files_from: Option<PathBuf>,
+    /// Exit with 2 if a snapshot was created and 0 if nothing changed
+    #[arg(long)]
+    detailed_exit_code: bool,
+    /// Archive even if the latest snapshot is younger than min_interval or there are more changes than
+    /// max_changed_files or max_change_ratio allow
+    #[arg(long)]
+    force: bool,
+    /// Diff against this snapshot instead of the latest one. The new snapshot branches off it: replay and
+    /// verify-chain assume each batch file applies to the previous snapshot and report it as a break
+    #[arg(long, value_name = "SNAPSHOT")]
+    base: Option<String>,
+    /// Skip if nothing was modified since the last successful run, as since_last_success = true
+    #[arg(long)]
+    since_last_success: bool,
+    /// Hard link unchanged files from the latest snapshot instead of copying it, as snapshot_strategy = "link-dest"
+    #[arg(long)]
+    no_base_copy: bool,
+    /// Note to record with the new snapshot, shown by list and stats
+    #[arg(long)]
+    note: Option<String>,
+    /// Snapshot the working dir even if it is empty, which is refused otherwise. The snapshot is never fast-forwarded
+    #[arg(long, requires = "force")]
+    archive_empty: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Action {
+    /// Create a new snapshot of the working dir, or of each of the [[targets]]
+    Archive(ArchiveArgs),
+    /// Only write the batch file and change list of a new snapshot, `apply` creates it later.
+    /// Until then doctor reports the snapshot as missing and no other run may create snapshots.
+    Extract {
+        config: String,
+    },
+    /// Create a snapshot left pending by `extract` from its batch file, the latest snapshot must be its base
+    Apply {
+        config: String,
+        /// Name of the pending snapshot, as printed by `extract`
+        snapshot: String,
+    },
+    /// Check the archive for problems left behind by crashed runs
+    Doctor {
+        config: String,
+        /// Perform safe automatic repairs, snapshots with files are never removed
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Check that every snapshot can be reached by replaying batch files from the oldest one
+    VerifyChain {
+        config: String,
+    },
+    /// Remove change lists and batch files older than the oldest snapshot
+    Gc {
+        config: String,
+    },
+    /// Show which files would be recorded as moved by the next archive run, without archiving
+    DetectMoves {
+        config: String,
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Copy a snapshot into a folder
+    Restore {
+        config: String,
+        /// Snapshot folder name
+        snapshot: String,
+        into: PathBuf,
+        /// Overwrite a non-empty folder, deleting files that are not in the snapshot, same as --on-conflict overwrite
+        #[arg(long, conflicts_with = "on_conflict")]
+        force: bool,
+        /// What to do with files already in a non-empty folder. --verify reports kept files and backups as mismatches
+        #[arg(long, value_enum)]
+        on_conflict: Option<OnConflict>,
+        /// Compare the restored files with the snapshot by content afterwards
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Restore an older snapshot and replay the recorded batch files on top of it up to a later one
+    Replay {
+        config: String,
+        /// Base snapshot folder name
+        from: String,
+        /// Snapshot to stop at, its folder may be gone as long as its batch file exists
+        to: String,
+        #[arg(long)]
+        into: PathBuf,
+    },
+    /// Remove snapshots not kept by the [retention] policy, pinned snapshots are never removed
+    Prune {
+        config: String,
+        /// Overrides retention.keep_last
+        #[arg(long)]
+        keep_last: Option<usize>,
+        /// Also keep snapshots whose note matches this glob pattern, can be repeated. Added to retention.keep_tagged
+        #[arg(long, value_name = "PATTERN")]
+        keep_tagged: Vec<String>,
+        /// Only show which snapshots are kept and why, and which would be removed
+        #[arg(long)]
+        plan: bool,
+        /// Check the Merkle roots of the kept snapshots first and remove nothing if one doesn't match. Snapshots
+        /// made without merkle_root = true can't be checked
+        #[arg(long)]
+        verify_retained: bool,
+    },
+    /// Write a snapshot as a tar stream to stdout, e.g. to pipe it into gpg
+    Export {
+        config: String,
+        /// Snapshot folder name
+        snapshot: String,
+    },
+    /// Protect a snapshot from prune
+    Pin {
+        config: String,
+        /// Snapshot folder name
+        snapshot: String,
+    },
+    /// List snapshots, oldest first
+    List {
+        config: String,
+    },
+    /// Show what the next archive run would record, without archiving
+    Diff {
+        config: String,
+    },
+    /// Estimate how many files and bytes the next archive run would transfer, with an rsync dry run
+    Estimate {
+        config: String,
+        /// Print the rsync statistics as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check that the latest snapshot still matches the working dir, exits with 1 if they differ
+    Compare {
+        config: String,
+    },
+    /// Show how each folder in the archive parses with date_format and legacy_date_formats, e.g. before changing
+    /// date_format. Exits with 1 if some folder with the configured prefix and suffix doesn't parse
+    FormatCheck {
+        config: String,
+    },
+    /// Rename snapshots and their sidecars from one date format to another, set date_format to the new one afterwards
+    MigrateFormat {
+        config: String,
+        /// Date format the snapshots are named with now
+        #[arg(long)]
+        from: String,
+        /// Date format to rename them to
+        #[arg(long)]
+        to: String,
+        /// Only show the renames
+        #[arg(long)]
+        dry_run: bool,
+        /// Leave folders that don't parse with --from as they are instead of aborting
+        #[arg(long)]
+        skip_unparseable: bool,
+    },
+    /// Show the change list recorded with a snapshot, grouped by kind and sorted by path
+    Changes {
+        config: String,
+        /// Snapshot folder name
+        snapshot: String,
+        /// Print the change list as JSON, as it is stored
+        #[arg(long)]
+        json: bool,
+        /// Only show paths matching this glob, e.g. `docs/**`
+        #[arg(long)]
+        filter: Option<String>,
+    },
+    /// Show the number of recorded changes per snapshot
+    Stats {
+        config: String,
+    },
+    /// Archive and restore a sample tree in a temp dir to check that the installation works
+    Selftest,
+    /// Show in which snapshots each version of a file exists
+    FindFile {
+        config: String,
+        /// Path relative to the working dir
+        path: PathBuf,
+        /// Only scan snapshots taken within this long, e.g. `30d`
+        #[arg(long)]
+        since: Option<String>,
+    },
+    /// Keep running and archive on the intervals from [[schedules]]
+    #[cfg(feature = "daemon")]
+    Daemon {
+        config: String,
+    },
+}
+
+fn load_config(path: &str, overrides: &ConfigOverrides) -> Result<Config> {
+    let config = load_config_with_overrides(path, overrides)?;
+    show_effective_config(&config, overrides)?;
+    Ok(config)
+}
+
+/// Loads the config at `path` with the global options applied.
+fn load_config_with_overrides(path: &str, overrides: &ConfigOverrides) -> Result<Config> {
+    let mut config = Config::load(&PathBuf::from(path).clean(), overrides.format)?;
+    register_secrets(config.secrets());
+    if let Some(flags) = &overrides.trace_rsync {
+        config.trace_rsync = flags.0.clone();
+    }
+    if let Some(tmpdir) = &overrides.tmpdir {
+        config.temp_dir = Some(tmpdir.clone());
+    }
+    Ok(config)
+}
+
+/// Prints `config` for `--print-effective-config`, or logs it at debug level.
+fn show_effective_config(config: &Config, overrides: &ConfigOverrides) -> Result<()> {
+    let effective = effective_config(config)?;
+    if overrides.print_effective_config {
+        eprintln!("{effective}");
+    } else {
+        debug!("effective config: {effective}");
+    }
+    Ok(())
+}
+
+/// `config` as JSON with credentials masked.
+fn effective_config(config: &Config) -> Result<String> {
+    Ok(redact(&serde_json::to_string_pretty(&config.redacted())?))
+}
+
+/// `load_config` for `archive`, with its options applied before the config is shown. The returned temp dir
+/// must outlive the config.
+fn load_archive_config(args: &ArchiveArgs, overrides: &ConfigOverrides) -> Result<(Config, LazyTempDir)> {
+    let mut config = load_config_with_overrides(&args.config, overrides)?;
+    config.base_snapshot = args.base.clone();
+    config.note = args.note.as_deref().map(sanitize_note).filter(|note| !note.is_empty());
+    config.archive_empty = args.archive_empty;
+    if args.no_base_copy {
+        config.snapshot_strategy = SnapshotStrategy::LinkDest;
+        config.check_strategy()?;
+    }
+    if args.force {
+        config.min_interval = None;
+        config.max_changed_files = None;
+        config.max_change_ratio = None;
+    }
+    config.write_file_list |= args.write_file_list;
+    config.since_last_success |= args.since_last_success;
+    if args.limit_depth == Some(0) {
+        return Err(anyhow!("--limit-depth must be at least 1"));
+    }
+    config.max_depth = args.limit_depth.or(config.max_depth);
+    let temp_dir = LazyTempDir::new(config.temp_dir.clone());
+    if let Some(files_from) = &args.files_from {
+        config.files_from = Some(if files_from == Path::new("-") {
+            // read once, every target gets the same list
+            let list = temp_dir.path()?.join("files-from");
+            std::io::copy(&mut std::io::stdin().lock(), &mut fs::File::create(&list)?)?;
+            list
+        } else {
+            files_from.clone()
+        });
+        config.check_strategy()?;
+    }
+    config.override_excludes(args.exclude_file.clone(), args.no_exclude);
+    config.exclude_add = args.exclude_add.clone();
+    show_effective_config(&config, overrides)?;
+    let config = config.with_inline_excludes(&temp_dir)?;
+    Ok((config, temp_dir))
+}
+
+/// `load_config` for commands that pass the excludes on to rsync: `exclude_inline` is written into the returned
+/// temp dir, which must outlive the config.
+fn load_config_with_excludes(path: &str, overrides: &ConfigOverrides) -> Result<(Config, LazyTempDir)> {
+    let config = load_config(path, overrides)?;
+    let temp_dir = LazyTempDir::new(config.temp_dir.clone());
+    let config = config.with_inline_excludes(&temp_dir)?;
+    Ok((config, temp_dir))
 }
 
-fn main() -> Result<()> {
-    let subscriber = FmtSubscriber::builder().with_max_level(Level::TRACE).compact().finish();
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+/// `load_config` for commands that read the working dir, with the same exclude rules as an archive run, see
+/// `Config::effective_excludes`. The returned temp dir must outlive the config.
+fn load_working_dir_config(path: &str, overrides: &ConfigOverrides) -> Result<(Config, LazyTempDir)> {
+    let config = load_config(path, overrides)?;
+    let temp_dir = LazyTempDir::new(config.temp_dir.clone());
+    let config = config.effective_excludes(&temp_dir)?;
+    Ok((config, temp_dir))
+}
+
+/// `read_meta` for listings, which show a snapshot with a broken `.meta.json` without its details.
+fn read_meta_or_warn(local_archive: &Path, name: &str) -> Option<SnapshotMeta> {
+    read_meta(local_archive, name).unwrap_or_else(|e| {
+        warn!("{e:#}");
+        None
+    })
+}
+
+fn print_findings(findings: &[Finding]) {
+    for finding in findings {
+        println!("[{}] {}", finding.severity, finding.message);
+        if let Some(suggestion) = &finding.suggestion {
+            println!("    suggestion: {suggestion}");
+        }
+    }
+}
+
+/// Lists what a `--keep-going` run failed on, an error if there is anything.
+fn check_failures(failures: &Failures, what: &str) -> Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    println!("failed on {} {what}:", failures.len());
+    for (path, e) in failures {
+        println!("    {}: {e:#}", path.display());
+    }
+    Err(anyhow!("failed on {} {what}", failures.len()))
+}
+
+/// Archives `config` with the exclude rules of the run appended to its exclude files.
+fn archive_with_excludes(config: &Config, temp_dir: &LazyTempDir) -> Result<ArchiveReport> {
+    archive_locked(&config.effective_excludes(temp_dir)?)
+}
 
+/// Archives `targets` on up to `max_parallel` threads, reports are in the order of `targets`. Targets sharing an
+/// archive are archived one after another, as they share its lock. After a failure no further targets are started
+/// and the first error is returned.
+fn archive_targets(targets: &[(String, Config)], temp_dir: &LazyTempDir, max_parallel: usize) -> Result<Vec<ArchiveReport>> {
+    let mut jobs: Vec<Vec<usize>> = Vec::new();
+    for (i, (_, config)) in targets.iter().enumerate() {
+        match jobs.iter_mut().find(|job| targets[job[0]].1.local_archive == config.local_archive) {
+            Some(job) => job.push(i),
+            None => jobs.push(vec![i]),
+        }
+    }
+    let jobs = Mutex::new(jobs.into_iter());
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..max_parallel.clamp(1, targets.len()) {
+            scope.spawn(|| {
+                while let Some(job) = jobs.lock().expect("job queue poisoned").next() {
+                    for i in job {
+                        if failed.load(Ordering::Relaxed) {
+                            return;
+                        }
+                        let (name, config) = &targets[i];
+                        let _span = info_span!("target", name = %name).entered();
+                        info!("archiving target {name}");
+                        let result = archive_with_excludes(config, temp_dir);
+                        if result.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        results.lock().expect("results poisoned").push((i, result));
+                    }
+                }
+            });
+        }
+    });
+    let mut results = results.into_inner().expect("results poisoned");
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+fn main() -> Result<ExitCode> {
     let args: Args = Args::parse();
+    // export writes the tar stream to stdout
+    let writer = match args.action {
+        Action::Export { .. } => BoxMakeWriter::new(RedactingMakeWriter(std::io::stderr)),
+        _ => BoxMakeWriter::new(RedactingMakeWriter(std::io::stdout)),
+    };
+    let builder = FmtSubscriber::builder().with_max_level(Level::TRACE).with_writer(writer);
+    match args.output_format {
+        OutputFormat::Text => tracing::subscriber::set_global_default(builder.compact().finish()),
+        OutputFormat::Json | OutputFormat::Ndjson => tracing::subscriber::set_global_default(builder.json().finish()),
+    }.expect("setting default subscriber failed");
+    let output = Output::new(args.plain);
+    let overrides = ConfigOverrides { format: args.config_format, trace_rsync: args.trace_rsync, tmpdir: args.tmpdir, print_effective_config: args.print_effective_config };
 
-    let config_path = PathBuf::from(args.config).clean();
-    let input = fs::read_to_string(config_path.clone())
-        .context(format!("unable to open {:?}", config_path))?;
-    let mut config: Config = toml::from_str(input.as_str())?;
+    match args.action {
+        Action::Archive(args) => {
+            let (config, temp_dir) = load_archive_config(&args, &overrides)?;
+            let targets = config.target_configs(args.target.as_deref())?;
+            let reports = if config.targets.is_empty() {
+                vec![archive_with_excludes(&targets[0].1, &temp_dir)?]
+            } else {
+                archive_targets(&targets, &temp_dir, config.max_parallel_targets.unwrap_or(1))?
+            };
+            for report in &reports {
+                println!("{report}");
+            }
+            if args.detailed_exit_code && reports.iter().any(|r| r.outcome == ArchiveOutcome::Created) {
+                return Ok(ExitCode::from(2));
+            }
+        }
+        Action::Extract { config } => {
+            let (config, _temp_dir) = load_working_dir_config(&config, &overrides)?;
+            let _lock = ArchiveLock::acquire(&config.local_archive)?;
+            if let Some(name) = extract_local(&config)? {
+                println!("{name}");
+            }
+        }
+        Action::Apply { config, snapshot } => {
+            let (config, _temp_dir) = load_working_dir_config(&config, &overrides)?;
+            let _lock = ArchiveLock::acquire(&config.local_archive)?;
+            apply_local(&config, &snapshot)?;
+        }
+        Action::Doctor { config, fix } => {
+            let config = load_config(&config, &overrides)?;
+            let findings = diagnose(&config.local_archive, &config.naming())?;
+            print_findings(&findings);
+            if fix && findings.iter().any(|f| f.fix.is_some()) {
+                println!("fixes to apply:");
+                for finding in findings.iter().filter(|f| f.fix.is_some()) {
+                    println!("    {}", finding.message);
+                }
+                if confirm("Apply these fixes?", args.assume_yes, config.confirm_non_interactive) {
+                    let lock = ArchiveLock::try_acquire(&config.local_archive)?;
+                    if lock.is_none() {
+                        warn!("an archive run is in progress, leaving staging and empty snapshot folders alone");
+                    }
+                    repair(&findings, &config.local_archive, &config.naming(), lock.is_none())?;
+                }
+            }
+            if findings.iter().any(|f| f.severity == Severity::Error) {
+                return Err(anyhow!("archive has errors"));
+            }
+        }
+        Action::VerifyChain { config } => {
+            let config = load_config(&config, &overrides)?;
+            let (findings, failures) = verify_chain(&config.local_archive, &config.naming(), args.keep_going)?;
+            print_findings(&findings);
+            check_failures(&failures, "batch files")?;
+            if findings.iter().any(|f| f.severity == Severity::Error) {
+                return Err(anyhow!("snapshot chain is broken"));
+            }
+        }
+        Action::Gc { config } => {
+            let config = load_config(&config, &overrides)?;
+            let lock = ArchiveLock::try_acquire(&config.local_archive)?;
+            if lock.is_none() {
+                warn!("an archive run is in progress, leaving staging folders alone");
+            }
+            let garbage = find_garbage(&config.local_archive, &config.naming(), lock.is_some())?;
+            if garbage.is_empty() {
+                info!("nothing to remove");
+                return Ok(ExitCode::SUCCESS);
+            }
+            for path in &garbage {
+                println!("{}", path.display());
+            }
+            if confirm(&format!("Remove these {} files?", garbage.len()), args.assume_yes, config.confirm_non_interactive) {
+                let failures = remove_garbage(&garbage, args.keep_going)?;
+                info!("removed {} files", garbage.len() - failures.len());
+                check_failures(&failures, "files")?;
+            }
+        }
+        Action::MigrateFormat { config, from, to, dry_run, skip_unparseable } => {
+            let config = load_config(&config, &overrides)?;
+            let _lock = ArchiveLock::acquire(&config.local_archive)?;
+            let renames = plan_migration(&config.local_archive, &config.naming(), &from, &to, skip_unparseable)?;
+            if renames.is_empty() {
+                info!("nothing to rename");
+                return Ok(ExitCode::SUCCESS);
+            }
+            for rename in &renames {
+                println!("{} -> {}", rename.from, rename.to);
+            }
+            if !dry_run && confirm(&format!("Rename these {} snapshots?", renames.len()), args.assume_yes, config.confirm_non_interactive) {
+                migrate_snapshots(&config.local_archive, &renames)?;
+                info!("renamed {} snapshots, set date_format = {to:?} in the config", renames.len());
+            }
+        }
+        Action::DetectMoves { config, json } => {
+            let (config, _temp_dir) = load_working_dir_config(&config, &overrides)?;
+            let (moved, deleted) = match diff_against_latest(&config)? {
+                Some(changed) => (changed.moved, changed.deleted),
+                None => (vec![], vec![])
+            };
+            if json {
+                let report = serde_json::json!({
+                    "moved": moved.iter().map(|(from, to)| serde_json::json!({ "from": from.path(), "to": to })).collect::<Vec<_>>(),
+                    "deleted": deleted.iter().map(|entity| entity.path()).collect::<Vec<_>>(),
+                });
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                let width = moved.iter().map(|(from, _)| from.path().as_os_str().len()).max().unwrap_or(0);
+                for (from, to) in &moved {
+                    println!("{:<width$}  ->  {}", from.path().display(), to.display());
+                }
+                for entity in &deleted {
+                    println!("{:<width$}  ->  (deleted)", entity.path().display());
+                }
+            }
+        }
+        Action::Restore { config, snapshot, into, force, on_conflict, verify } => {
+            let (config, _temp_dir) = load_config_with_excludes(&config, &overrides)?;
+            let on_conflict = if force { Some(OnConflict::Overwrite) } else { on_conflict };
+            if on_conflict == Some(OnConflict::Overwrite) && !is_empty_dir(&into)? {
+                let prompt = format!("Files in {into:?} that are not in {snapshot} will be deleted, continue?");
+                if !confirm(&prompt, args.assume_yes, config.confirm_non_interactive) {
+                    return Ok(ExitCode::SUCCESS);
+                }
+            }
+            restore_snapshot(&config, &snapshot, &into, on_conflict)?;
+            if verify {
+                verify_restore(&config, &snapshot, &into)?;
+            }
+        }
+        Action::Replay { config, from, to, into } => {
+            let (config, _temp_dir) = load_config_with_excludes(&config, &overrides)?;
+            replay_snapshots(&config, &from, &to, &into)?;
+        }
+        Action::Prune { config, keep_last, keep_tagged, plan, verify_retained: verify } => {
+            let config = load_config(&config, &overrides)?;
+            let mut policy = config.retention.clone();
+            if keep_last.is_some() {
+                policy.keep_last = keep_last;
+            }
+            policy.keep_tagged.extend(keep_tagged);
+            if plan {
+                let rows = retention_plan(&config.local_archive, &config.naming(), &policy)?.into_iter()
+                    .map(|(name, reasons)| {
+                        let verdict = if reasons.is_empty() {
+                            Field::colored("remove", Color::Red)
+                        } else {
+                            Field::colored("keep", Color::Green)
+                        };
+                        let reasons = reasons.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(", ");
+                        vec![Field::new(name), verdict, Field::new(reasons)]
+                    })
+                    .collect();
+                output.print(&["Snapshot", "Plan", "Kept by"], rows);
+                return Ok(ExitCode::SUCCESS);
+            }
+            let _lock = ArchiveLock::acquire(&config.local_archive)?;
+            let prunable = find_prunable(&config.local_archive, &config.naming(), &policy)?;
+            if prunable.is_empty() {
+                info!("nothing to remove");
+                return Ok(ExitCode::SUCCESS);
+            }
+            if verify {
+                let temp_dir = LazyTempDir::new(config.temp_dir.clone());
+                let config = config.with_inline_excludes(&temp_dir)?;
+                verify_retained(&config, &policy)?;
+            }
+            for path in &prunable {
+                println!("{}", path.display());
+            }
+            if confirm(&format!("Remove these {} snapshots?", prunable.len()), args.assume_yes, config.confirm_non_interactive) {
+                let failures = remove_snapshots(&prunable, args.keep_going)?;
+                info!("removed {} snapshots, run gc to remove their change lists", prunable.len() - failures.len());
+                check_failures(&failures, "snapshots")?;
+            }
+        }
+        Action::Export { config, snapshot } => {
+            let config = load_config(&config, &overrides)?;
+            let path = config.local_archive.join(&snapshot);
+            if config.naming().parse(&snapshot).is_none() || !path.is_dir() {
+                return Err(anyhow!("there is no snapshot {snapshot:?} in {:?}", config.local_archive));
+            }
+            let stdout = std::io::stdout();
+            if stdout.is_terminal() {
+                return Err(anyhow!("stdout is a terminal, redirect it to a file or pipe"));
+            }
+            export_snapshot(&path, BufWriter::new(stdout.lock()))?;
+        }
+        Action::Pin { config, snapshot } => {
+            let config = load_config(&config, &overrides)?;
+            pin_snapshot(&config.local_archive, &config.naming(), &snapshot)?;
+        }
+        Action::List { config } => {
+            let config = load_config(&config, &overrides)?;
+            let rows = timestamp_named_folders(&config.local_archive, &config.naming())?.into_iter()
+                .map(|(timestamp, name)| {
+                    let pinned = if is_pinned(&config.local_archive, &name) {
+                        Field::colored("pinned", Color::Green)
+                    } else {
+                        Field::new("")
+                    };
+                    let note = read_meta_or_warn(&config.local_archive, &name).and_then(|meta| meta.note).unwrap_or_default();
+                    vec![Field::new(name), Field::new(human_timestamp(&timestamp)), pinned, Field::new(note)]
+                })
+                .collect();
+            output.print(&["Snapshot", "Taken", "Pinned", "Note"], rows);
+        }
+        Action::Diff { config } => {
+            let (config, _temp_dir) = load_working_dir_config(&config, &overrides)?;
+            if config.merkle_root {
+                if let Some(latest) = latest_matching_merkle_root(&config)? {
+                    info!("no changes, the working dir has the same Merkle root as {latest}");
+                    return Ok(ExitCode::SUCCESS);
+                }
+            }
+            match diff_against_latest(&config)? {
+                Some(changes) => output.print(&["Change", "Path"], change_rows(&changes)),
+                None => info!("no changes")
+            }
+        }
+        Action::Estimate { config, json } => {
+            let (config, _temp_dir) = load_working_dir_config(&config, &overrides)?;
+            let stats = estimate_transfer(&config)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                output.print(&["Estimate", "Value"], vec![
+                    vec![Field::new("files to transfer"), Field::new(stats.regular_files_transferred.to_string())],
+                    vec![Field::new("bytes to transfer"), Field::new(human_size(stats.total_transferred_file_size))],
+                    vec![Field::new("files to delete"), Field::new(stats.deleted_files.to_string())],
+                    vec![Field::new("files in the working dir"), Field::new(stats.files.to_string())],
+                    vec![Field::new("working dir size"), Field::new(human_size(stats.total_file_size))],
+                ]);
+            }
+        }
+        Action::Compare { config } => {
+            let (config, _temp_dir) = load_working_dir_config(&config, &overrides)?;
+            if config.merkle_root {
+                if let Some(latest) = latest_matching_merkle_root(&config)? {
+                    println!("latest snapshot {latest} is identical to {} (same Merkle root)", config.local_working_dir.display());
+                    return Ok(ExitCode::SUCCESS);
+                }
+            }
+            match diff_against_latest(&config)? {
+                Some(changes) => {
+                    output.print(&["Change", "Path"], change_rows(&changes));
+                    return Ok(ExitCode::FAILURE);
+                }
+                None => println!("latest snapshot matches {}", config.local_working_dir.display())
+            }
+        }
+        Action::Stats { config } => {
+            let config = load_config(&config, &overrides)?;
+            let rows = timestamp_named_folders(&config.local_archive, &config.naming())?.into_iter()
+                .map(|(timestamp, name)| {
+                    let meta = read_meta_or_warn(&config.local_archive, &name);
+                    let counts = match (meta.as_ref().and_then(|meta| meta.baseline_file_count), read_change_list(&config.local_archive, &name)) {
+                        (Some(files), _) => [Field::new(format!("baseline, {files} files")), Field::new("-"), Field::new("-")],
+                        (None, Some(changes)) => [changes.changed.len(), changes.moved.len(), changes.deleted.len()].map(|n| Field::new(n.to_string())),
+                        (None, None) => [(); 3].map(|_| Field::new("-")),
+                    };
+                    let note = meta.and_then(|meta| meta.note).unwrap_or_default();
+                    let mut row = vec![Field::new(name), Field::new(human_timestamp(&timestamp))];
+                    row.extend(counts);
+                    row.push(Field::new(note));
+                    row
+                })
+                .collect();
+            output.print(&["Snapshot", "Taken", "Changed", "Moved", "Deleted", "Note"], rows);
+        }
+        Action::Changes { config, snapshot, json, filter } => {
+            let config = load_config(&config, &overrides)?;
+            if !config.local_archive.join(&snapshot).is_dir() {
+                return Err(anyhow!("there is no snapshot {snapshot:?}"));
+            }
+            let mut changes = read_change_list(&config.local_archive, &snapshot).ok_or(anyhow!(
+                "{snapshot} has no readable change list: it is the empty base snapshot, its run was interrupted or it predates change lists"))?;
+            if let Some(filter) = filter {
+                changes = changes.filtered(&Pattern::new(&filter).context(format!("invalid --filter {filter:?}"))?);
+            }
+            changes.sort();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&changes)?);
+            } else {
+                output.print(&["Change", "Path"], change_rows(&changes));
+            }
+        }
+        Action::FormatCheck { config } => {
+            let config = load_config(&config, &overrides)?;
+            let checked = check_folder_names(&config.local_archive, &config.naming())?;
+            let unparseable = checked.iter().any(|(_, kind)| *kind == FolderName::Unparseable);
+            let rows = checked.into_iter()
+                .map(|(name, kind)| {
+                    let kind = match kind {
+                        FolderName::Current => Field::colored("date_format", Color::Green),
+                        FolderName::Legacy(format) => Field::colored(format!("legacy {format}"), Color::Yellow),
+                        FolderName::Unparseable => Field::colored("doesn't parse", Color::Red),
+                        FolderName::OtherAffixes => Field::new("other prefix or suffix"),
+                        FolderName::Ignored => Field::new("ignored"),
+                    };
+                    vec![Field::new(name), kind]
+                })
+                .collect();
+            output.print(&["Folder", "Parses with"], rows);
+            if unparseable {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Action::FindFile { config, path, since } => {
+            let config = load_config(&config, &overrides)?;
+            let since = match since {
+                Some(since) => Some(Local::now().fixed_offset() - chrono::Duration::from_std(parse_duration(&since)?)?),
+                None => None
+            };
+            let versions = find_file_versions(&config.local_archive, &config.naming(), &path, since)?;
+            let rows = versions.into_iter()
+                .map(|v| vec![Field::new(v.first_seen), Field::new(v.last_seen), Field::new(v.size.to_string()), Field::new(v.hash.to_hex()[..16].to_owned())])
+                .collect();
+            output.print(&["First seen", "Last seen", "Size", "Hash"], rows);
+        }
+        Action::Selftest => {
+            vhbarchsync::selftest::selftest()?;
+        }
+        #[cfg(feature = "daemon")]
+        Action::Daemon { config } => {
+            let config = load_config(&config, &overrides)?;
+            vhbarchsync::daemon::run_daemon(&config)?;
+        }
+    }
 
-    // remove trailing slashes and add later only if needed
-    remove_trailing_slash(&mut config.local_archive);
-    remove_trailing_slash(&mut  config.local_working_dir);
+    Ok(ExitCode::SUCCESS)
+}
 
-    // let temp_dir = tempdir()?;
-    // let exclude_filename = temp_dir.path().join("exclude.txt");
-    // let mut exclude_file = File::create(exclude_filename.clone())?;
-    // for exclude_pattern in &config.exclude {
-    //     exclude_file.write_all(exclude_pattern.as_str().as_bytes())?;
-    //     exclude_file.write_all("\n".as_bytes())?;
-    // }
-    // exclude_file.sync_data()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if args.action == "archive" {
-        archive_local(&config.local_working_dir, &config.local_archive, &config.exclude, config.date_format.as_str())?;
+    #[test]
+    fn exclude_file_conflicts_with_no_exclude() {
+        let args = Args::try_parse_from(["vhbarchsync", "archive", "c.toml", "--exclude-file", "e.txt", "--no-exclude"]);
+        assert_eq!(args.unwrap_err().kind(), clap::error::ErrorKind::ArgumentConflict);
     }
 
-    Ok(())
+    #[test]
+    fn effective_config_shows_the_archive_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let file = serde_json::json!({
+            "local_working_dir": dir.path().join("work"),
+            "local_archive": dir.path().join("archive"),
+            "exclude": dir.path().join("exclude.txt"),
+            "max_depth": 5,
+        });
+        fs::write(&path, file.to_string()).unwrap();
+        let args = Args::try_parse_from(["vhbarchsync", "archive", path.to_str().unwrap(), "--exclude-add", "*.tmp", "--limit-depth", "2"]).unwrap();
+        let Action::Archive(args) = args.action else { panic!("not an archive") };
+        let overrides = ConfigOverrides { format: None, trace_rsync: None, tmpdir: None, print_effective_config: false };
+
+        let (config, _temp_dir) = load_archive_config(&args, &overrides).unwrap();
+        let effective: serde_json::Value = serde_json::from_str(&effective_config(&config).unwrap()).unwrap();
+        assert_eq!(effective["exclude_add"], serde_json::json!(["*.tmp"]));
+        assert_eq!(effective["max_depth"], 2);
+    }
+
+    /// Target archiving `root/work` into `root/archive`, deferred by backpressure so that it finishes without rsync.
+    fn deferred_target(root: &Path) -> Config {
+        fs::create_dir_all(root.join("work")).unwrap();
+        fs::create_dir_all(root.join("archive")).unwrap();
+        fs::write(root.join("exclude.txt"), "").unwrap();
+        serde_json::from_value(serde_json::json!({
+            "local_working_dir": root.join("work"),
+            "local_archive": root.join("archive"),
+            "exclude": root.join("exclude.txt"),
+            "backpressure": { "min_free": "1000000T" },
+        })).unwrap()
+    }
+
+    #[test]
+    fn parallel_targets_all_complete_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut targets: Vec<(String, Config)> = (0..5)
+            .map(|i| (format!("target{i}"), deferred_target(&dir.path().join(format!("target{i}")))))
+            .collect();
+        // shares the archive of target0, runs after it on the same thread
+        let mut shared = deferred_target(&dir.path().join("shared"));
+        shared.local_archive = targets[0].1.local_archive.clone();
+        targets.push(("shared".to_owned(), shared));
+
+        let reports = archive_targets(&targets, &LazyTempDir::new(None), 3).unwrap();
+        assert_eq!(reports.len(), targets.len());
+        assert!(reports.iter().all(|report| report.outcome == ArchiveOutcome::Deferred));
+    }
+
+    #[test]
+    fn failing_target_fails_the_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut broken = deferred_target(&dir.path().join("broken"));
+        broken.local_archive = dir.path().join("missing");
+        let targets = vec![("ok".to_owned(), deferred_target(&dir.path().join("ok"))), ("broken".to_owned(), broken)];
+        assert!(archive_targets(&targets, &LazyTempDir::new(None), 2).is_err());
+    }
+
+    #[test]
+    fn archive_empty_requires_force() {
+        let args = Args::try_parse_from(["vhbarchsync", "archive", "c.toml", "--archive-empty"]);
+        assert_eq!(args.unwrap_err().kind(), clap::error::ErrorKind::MissingRequiredArgument);
+        let args = Args::try_parse_from(["vhbarchsync", "archive", "c.toml", "--archive-empty", "--force"]);
+        assert!(matches!(args.unwrap().action, Action::Archive(args) if args.archive_empty));
+    }
+
+    #[test]
+    fn exclude_add_combines_with_either_override() {
+        for flag in [["--exclude-file", "e.txt"], ["--no-exclude", "--exclude-add=*.log"]] {
+            let args = Args::try_parse_from(["vhbarchsync", "archive", "c.toml", "--exclude-add", "*.tmp"].into_iter().chain(flag));
+            assert!(matches!(args.unwrap().action, Action::Archive(args) if args.exclude_add.contains(&"*.tmp".to_owned())));
+        }
+    }
 }
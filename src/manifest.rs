@@ -0,0 +1,340 @@
+//! Content manifests of folders, used to check restored trees against their snapshot, and other walks of a
+//! working dir that honor its exclude file.
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use crate::util::{hash_file, walk_files};
+
+/// Relative path -> blake3 hash of the contents.
+pub type Manifest = BTreeMap<PathBuf, blake3::Hash>;
+
+struct ExcludeRule {
+    pattern: Pattern,
+    include: bool,
+    anchored: bool,
+    dir_only: bool,
+    /// Contains a `/` other than a leading or trailing one, matched against whole paths instead of names
+    has_slash: bool,
+}
+
+/// Matches paths against an rsync exclude file. Covers the common subset: `- `/`+ ` prefixes, comments,
+/// anchored `/patterns`, trailing `/` for folders and `*`, `**`, `?`, `[..]` wildcards. Merge and modifier rules
+/// are not supported and are ignored.
+pub struct ExcludeMatcher {
+    rules: Vec<ExcludeRule>,
+}
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+impl ExcludeMatcher {
+    /// Rules of all `exclude_files` in order, as rsync reads them from repeated `--exclude-from`.
+    pub fn load(exclude_files: &[PathBuf]) -> Result<Self> {
+        let mut contents = String::new();
+        for exclude_file in exclude_files {
+            contents += &fs::read_to_string(exclude_file).context(format!("unable to open {exclude_file:?}"))?;
+            contents.push('\n');
+        }
+        let mut rules = Vec::new();
+        for line in contents.lines() {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let (include, pattern) = if let Some(pattern) = line.strip_prefix("+ ") {
+                (true, pattern)
+            } else if let Some(pattern) = line.strip_prefix("- ") {
+                (false, pattern)
+            } else if line.starts_with([':', '.', '!', 'P', 'R', 'H', 'S']) && line.get(1..2) == Some(" ") {
+                // merge, clear and protect rules don't affect which files are transferred here
+                continue;
+            } else {
+                (false, line)
+            };
+            let anchored = pattern.starts_with('/');
+            let dir_only = pattern.ends_with('/');
+            let trimmed = pattern.trim_start_matches('/').trim_end_matches('/');
+            rules.push(ExcludeRule {
+                pattern: Pattern::new(trimmed).context(format!("invalid exclude pattern {pattern:?}"))?,
+                include,
+                anchored,
+                dir_only,
+                has_slash: trimmed.contains('/'),
+            });
+        }
+        Ok(ExcludeMatcher { rules })
+    }
+
+    fn rule_matches(rule: &ExcludeRule, relative: &str, is_dir: bool) -> bool {
+        if rule.dir_only && !is_dir {
+            return false;
+        }
+        if rule.anchored {
+            return rule.pattern.matches_with(relative, MATCH_OPTIONS);
+        }
+        if !rule.has_slash {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            return rule.pattern.matches_with(name, MATCH_OPTIONS);
+        }
+        // unanchored patterns with a slash match any trailing part of the path that starts at a folder boundary
+        let mut suffix = relative;
+        loop {
+            if rule.pattern.matches_with(suffix, MATCH_OPTIONS) {
+                return true;
+            }
+            match suffix.split_once('/') {
+                Some((_, rest)) => suffix = rest,
+                None => return false
+            }
+        }
+    }
+
+    fn excludes_entry(&self, relative: &str, is_dir: bool) -> bool {
+        self.rules.iter()
+            .find(|rule| Self::rule_matches(rule, relative, is_dir))
+            .is_some_and(|rule| !rule.include)
+    }
+
+    /// True if the file at `relative` or any of its parent folders is excluded.
+    pub fn is_excluded(&self, relative: &Path) -> bool {
+        let components: Vec<String> = relative.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect();
+        for i in 1..=components.len() {
+            if self.excludes_entry(&components[..i].join("/"), i < components.len()) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Hashes every regular file below `dir` that is not excluded.
+pub fn build_manifest(dir: &Path, excludes: &ExcludeMatcher) -> Result<Manifest> {
+    let mut manifest = Manifest::new();
+    for file in walk_files(dir).context(format!("walking {dir:?}"))? {
+        let relative = file.strip_prefix(dir)?.to_path_buf();
+        if excludes.is_excluded(&relative) {
+            continue;
+        }
+        manifest.insert(relative, hash_file(&file)?);
+    }
+    Ok(manifest)
+}
+
+/// Root hash over the regular files below `dir` that are not excluded: blake3 of the leaf hashes in path order,
+/// each leaf hashing the relative path, size and content hash of one file. Symlinks, empty folders and file
+/// metadata are not covered.
+pub fn snapshot_merkle_root(dir: &Path, excludes: &ExcludeMatcher) -> Result<blake3::Hash> {
+    let mut root = blake3::Hasher::new();
+    for (relative, hash) in build_manifest(dir, excludes)? {
+        let path = dir.join(&relative);
+        let size = fs::metadata(&path).context(format!("reading metadata of {path:?}"))?.len();
+        let mut leaf = blake3::Hasher::new();
+        leaf.update(relative.as_os_str().as_bytes());
+        leaf.update(&[0]);
+        leaf.update(&size.to_le_bytes());
+        leaf.update(hash.as_bytes());
+        root.update(leaf.finalize().as_bytes());
+    }
+    Ok(root.finalize())
+}
+
+/// Exclude rules for the files and folders below `dir` owned by one of `uids`, folders are excluded as a whole.
+/// Entries already excluded by `excludes` are not visited.
+pub fn owner_exclude_rules(dir: &Path, excludes: &ExcludeMatcher, uids: &[u32]) -> Result<Vec<String>> {
+    let mut rules = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(folder) = pending.pop() {
+        for entry in fs::read_dir(&folder).context(format!("reading {folder:?}"))? {
+            let path = entry?.path();
+            let relative = path.strip_prefix(dir)?;
+            if excludes.is_excluded(relative) {
+                continue;
+            }
+            let metadata = fs::symlink_metadata(&path).context(format!("reading metadata of {path:?}"))?;
+            let is_dir = metadata.is_dir();
+            if uids.contains(&metadata.uid()) {
+                rules.push(format!("- /{}{}", escape_rule(&relative.to_string_lossy()), if is_dir { "/" } else { "" }));
+            } else if is_dir {
+                pending.push(path);
+            }
+        }
+    }
+    rules.sort();
+    Ok(rules)
+}
+
+/// FIFOs, sockets and device nodes below `dir` that are not excluded by `excludes`.
+pub fn find_special_files(dir: &Path, excludes: &ExcludeMatcher) -> Result<Vec<PathBuf>> {
+    let mut special = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(folder) = pending.pop() {
+        for entry in fs::read_dir(&folder).context(format!("reading {folder:?}"))? {
+            let entry = entry?;
+            let path = entry.path();
+            if excludes.is_excluded(path.strip_prefix(dir)?) {
+                continue;
+            }
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(path);
+            } else if file_type.is_fifo() || file_type.is_socket() || file_type.is_block_device() || file_type.is_char_device() {
+                special.push(path);
+            }
+        }
+    }
+    special.sort();
+    Ok(special)
+}
+
+/// rsync treats backslashes literally unless a pattern contains wildcards, so only those get escaped.
+pub fn escape_rule(path: &str) -> String {
+    if !path.contains(['*', '?', '[']) {
+        return path.to_owned();
+    }
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Describes the differences between two manifests, empty if they are equal.
+pub fn compare_manifests(expected: &Manifest, actual: &Manifest) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    for (path, hash) in expected {
+        match actual.get(path) {
+            None => mismatches.push(format!("missing {path:?}")),
+            Some(actual_hash) if actual_hash != hash => mismatches.push(format!("different contents of {path:?}")),
+            Some(_) => {}
+        }
+    }
+    for path in actual.keys().filter(|path| !expected.contains_key(*path)) {
+        mismatches.push(format!("unexpected {path:?}"));
+    }
+    if expected.len() != actual.len() {
+        mismatches.push(format!("{} files expected, {} found", expected.len(), actual.len()));
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(rules: &str) -> ExcludeMatcher {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), rules).unwrap();
+        ExcludeMatcher::load(&[file.path().to_path_buf()]).unwrap()
+    }
+
+    fn write_tree(dir: &Path, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn exclude_files_are_read_in_order_and_the_first_match_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let (base, target) = (dir.path().join("base.txt"), dir.path().join("target.txt"));
+        fs::write(&base, "+ keep.log\n/cache/\n").unwrap();
+        fs::write(&target, "*.log\n+ cache/\n").unwrap();
+        let excludes = ExcludeMatcher::load(&[base.clone(), target.clone()]).unwrap();
+        assert!(!excludes.is_excluded(Path::new("keep.log")));
+        assert!(excludes.is_excluded(Path::new("other.log")));
+        assert!(excludes.is_excluded(Path::new("cache/data.bin")));
+        // the other way round the target rules come first
+        let excludes = ExcludeMatcher::load(&[target, base]).unwrap();
+        assert!(excludes.is_excluded(Path::new("keep.log")));
+        assert!(!excludes.is_excluded(Path::new("cache/data.bin")));
+    }
+
+    #[test]
+    fn exclude_rules_match_as_rsync_does() {
+        // rules, path, is a folder, excluded
+        let cases = [
+            // unanchored patterns without a slash match the name at any depth
+            ("*.tmp", "a.tmp", false, true),
+            ("*.tmp", "sub/deep/a.tmp", false, true),
+            ("*.tmp", "a.tmp.txt", false, false),
+            // a leading slash anchors to the top of the transfer
+            ("/build", "build", true, true),
+            ("/build", "src/build", true, false),
+            // a trailing slash matches folders only, and everything below them
+            ("cache/", "cache", false, false),
+            ("cache/", "cache/a/b.txt", false, true),
+            ("cache/", "sub/cache/b.txt", false, true),
+            // with an inner slash the pattern matches the end of the path at a folder boundary
+            ("src/*.o", "src/a.o", false, true),
+            ("src/*.o", "lib/src/a.o", false, true),
+            ("src/*.o", "mysrc/a.o", false, false),
+            // `*` stops at slashes, `**` doesn't
+            ("/a/*/c", "a/x/c", false, true),
+            ("/a/*/c", "a/x/y/c", false, false),
+            ("/a/**/c", "a/x/y/c", false, true),
+            ("/logs/**", "logs/2024/01/app.log", false, true),
+            // the first matching rule wins
+            ("+ keep.log\n*.log", "keep.log", false, false),
+            ("+ keep.log\n*.log", "other.log", false, true),
+            ("*.log\n+ keep.log", "keep.log", false, true),
+            ("- *.log\n+ *", "a.log", false, true),
+            // nothing below an excluded folder comes back
+            ("- /data/\n+ /data/keep.txt", "data/keep.txt", false, true),
+            // comments and blank lines are not rules
+            ("# *.txt\n\n; *.txt", "a.txt", false, false),
+        ];
+        for (rules, path, is_dir, excluded) in cases {
+            let matcher = matcher(rules);
+            let result = if is_dir {
+                matcher.excludes_entry(path, true)
+            } else {
+                matcher.is_excluded(Path::new(path))
+            };
+            assert_eq!(result, excluded, "rules {rules:?}, path {path:?}");
+        }
+    }
+
+    #[test]
+    fn merkle_root_depends_on_paths_and_contents_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        let files = [("file.txt", "contents"), ("sub/other.txt", "other")];
+        write_tree(&a, &files);
+        write_tree(&b, &files);
+        let none = matcher("");
+        let root = snapshot_merkle_root(&a, &none).unwrap();
+        assert_eq!(snapshot_merkle_root(&a, &none).unwrap(), root);
+        // another folder and other mtimes
+        fs::File::options().write(true).open(b.join("file.txt")).unwrap()
+            .set_modified(std::time::SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(snapshot_merkle_root(&b, &none).unwrap(), root);
+
+        fs::write(b.join("file.txt"), "changed").unwrap();
+        assert_ne!(snapshot_merkle_root(&b, &none).unwrap(), root);
+        fs::write(b.join("file.txt"), "contents").unwrap();
+        fs::rename(b.join("sub/other.txt"), b.join("sub/renamed.txt")).unwrap();
+        assert_ne!(snapshot_merkle_root(&b, &none).unwrap(), root);
+    }
+
+    #[test]
+    fn merkle_root_skips_excluded_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write_tree(dir.path(), &[("file.txt", "contents")]);
+        let excludes = matcher("*.tmp\n");
+        let root = snapshot_merkle_root(dir.path(), &excludes).unwrap();
+        write_tree(dir.path(), &[("scratch.tmp", "scratch")]);
+        assert_eq!(snapshot_merkle_root(dir.path(), &excludes).unwrap(), root);
+        assert_ne!(snapshot_merkle_root(dir.path(), &matcher("")).unwrap(), root);
+    }
+}
@@ -0,0 +1,157 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use crate::config::{Config, SnapshotStrategy};
+use crate::syncer_util::{sidecar_path, META_EXT};
+
+/// Contents of the `<snapshot>.meta.json` sidecar: how a snapshot was produced.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotMeta {
+    pub tool_version: String,
+    pub hostname: String,
+    /// RFC 3339
+    pub started: String,
+    pub finished: String,
+    /// Command lines of the external tools that were run
+    pub commands: Vec<String>,
+    /// Snapshot the batch file applies to, the previous one unless created with `archive --base`. Following these
+    /// links gives the history even when `--base` branched it, the empty snapshot `init` starts with has none.
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// Commit checked out in the working dir, with `[git] record_head`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_head: Option<String>,
+    /// From `archive --note`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    /// Hex `snapshot_merkle_root` of the snapshot, with `merkle_root = true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merkle_root: Option<String>,
+    /// Files in the first snapshot after the empty base one, its change list records all of them as changed, so
+    /// `stats` shows it as a baseline instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseline_file_count: Option<usize>,
+    /// Settings the snapshot was made with
+    #[serde(default)]
+    pub config: RunConfig,
+}
+
+/// The parts of the config that shape a snapshot. Older versions recorded the whole config here, its other
+/// fields are ignored when reading.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RunConfig {
+    #[serde(default)]
+    pub snapshot_strategy: SnapshotStrategy,
+    /// Rules of all exclude files in the order rsync got them, the generated ones included
+    #[serde(default)]
+    pub excludes: Vec<String>,
+    /// rsync options the config turned into, given to the diff and apply runs
+    #[serde(default)]
+    pub rsync_args: Vec<String>,
+}
+
+impl RunConfig {
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut excludes = Vec::new();
+        for file in &config.exclude {
+            let rules = fs::read_to_string(file).context(format!("reading {file:?}"))?;
+            excludes.extend(rules.lines().filter(|line| !line.trim().is_empty()).map(str::to_owned));
+        }
+        Ok(RunConfig {
+            snapshot_strategy: config.snapshot_strategy,
+            excludes,
+            rsync_args: config.rsync_options().to_args().iter().map(|arg| arg.to_string_lossy().into_owned()).collect(),
+        })
+    }
+}
+
+impl SnapshotMeta {
+    pub fn new(config: &Config, started: DateTime<Local>, commands: Vec<String>, parent: &str, git_head: Option<String>) -> Result<Self> {
+        Ok(SnapshotMeta {
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+            started: started.to_rfc3339(),
+            finished: Local::now().to_rfc3339(),
+            commands,
+            parent: Some(parent.to_owned()),
+            git_head,
+            note: config.note.clone(),
+            merkle_root: None,
+            baseline_file_count: None,
+            config: RunConfig::new(config)?,
+        })
+    }
+}
+
+/// `.meta.json` of snapshot `name`, None if there is none.
+pub fn read_meta(local_archive: &Path, name: &str) -> Result<Option<SnapshotMeta>> {
+    let path = sidecar_path(local_archive, name, META_EXT);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(format!("reading {path:?}")),
+    };
+    serde_json::from_str(&contents).map(Some).context(format!("parsing {path:?}"))
+}
+
+/// A note on one line: control characters like newlines become spaces.
+pub fn sanitize_note(note: &str) -> String {
+    note.chars().map(|c| if c.is_control() { ' ' } else { c }).collect::<String>().trim().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_config;
+
+    #[test]
+    fn meta_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path());
+        fs::write(&config.exclude[0], "*.tmp\n\n/build/\n").unwrap();
+        config.sparse = true;
+        config.note = Some("before the upgrade".to_owned());
+        let name = config.naming().format(&Local::now());
+        let mut meta = SnapshotMeta::new(&config, Local::now(), vec!["rsync -a".to_owned()], "parent", None).unwrap();
+        meta.merkle_root = Some("00ff".to_owned());
+        fs::write(sidecar_path(&config.local_archive, &name, META_EXT), serde_json::to_string(&meta).unwrap()).unwrap();
+
+        let read = read_meta(&config.local_archive, &name).unwrap().unwrap();
+        assert_eq!(read.parent.as_deref(), Some("parent"));
+        assert_eq!(read.note, meta.note);
+        assert_eq!(read.merkle_root, meta.merkle_root);
+        assert_eq!(read.commands, meta.commands);
+        assert_eq!(read.config, meta.config);
+        assert_eq!(read.config.excludes, ["*.tmp", "/build/"]);
+        assert!(read.config.rsync_args.contains(&"--sparse".to_owned()));
+    }
+
+    #[test]
+    fn missing_meta_is_none_and_a_broken_one_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read_meta(dir.path(), "snapshot").unwrap().is_none());
+        fs::write(sidecar_path(dir.path(), "snapshot", META_EXT), "{ not json").unwrap();
+        assert!(read_meta(dir.path(), "snapshot").is_err());
+    }
+
+    #[test]
+    fn meta_with_the_whole_config_still_parses() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = serde_json::json!({
+            "tool_version": "0.1.0",
+            "hostname": "host",
+            "started": "2024-01-01T00:00:00+00:00",
+            "finished": "2024-01-01T00:01:00+00:00",
+            "commands": [],
+            "parent": "parent",
+            "config": {"local_working_dir": "/work", "local_archive": "/archive", "exclude": ["/exclude.txt"], "snapshot_strategy": "link-dest"},
+        });
+        fs::write(sidecar_path(dir.path(), "snapshot", META_EXT), old.to_string()).unwrap();
+        let meta = read_meta(dir.path(), "snapshot").unwrap().unwrap();
+        assert_eq!(meta.parent.as_deref(), Some("parent"));
+        assert_eq!(meta.config.snapshot_strategy, SnapshotStrategy::LinkDest);
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use anyhow::{Context, Result};
+use chrono::Local;
+use crate::archive::ArchiveReport;
+use crate::config::Config;
+use crate::syncer_util::{count_timestamp_named_folders, find_diff_file};
+
+/// Metrics of one archive run in the Prometheus text exposition format, for the node exporter textfile collector.
+pub fn render_metrics(report: Option<&ArchiveReport>, batch_file_bytes: u64, snapshot_count: usize) -> String {
+    let changed = report.and_then(|report| report.changes.as_ref()).map_or(0, |changes| changes.changed.len());
+    let metrics: [(&str, &str, String); 5] = [
+        ("vhbarchsyn_last_run_timestamp", "Unix time the last archive run finished", Local::now().timestamp().to_string()),
+        ("vhbarchsyn_last_run_success", "1 if the last archive run succeeded", u8::from(report.is_some()).to_string()),
+        ("vhbarchsyn_changed_files", "Files changed in the snapshot created by the last run", changed.to_string()),
+        ("vhbarchsyn_batch_file_bytes", "Size in bytes of the batch file of the snapshot created by the last run, 0 without one", batch_file_bytes.to_string()),
+        ("vhbarchsyn_snapshot_count", "Snapshots in the archive", snapshot_count.to_string()),
+    ];
+    let mut out = String::new();
+    for (name, help, value) in metrics {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {value}");
+    }
+    out
+}
+
+/// Writes the metrics of a run to `path`. The file is replaced by a rename, so the collector never reads half of it.
+pub fn write_metrics(path: &Path, config: &Config, report: Option<&ArchiveReport>) -> Result<()> {
+    let batch_file_bytes = report.and_then(|report| report.snapshot.as_ref())
+        .and_then(|name| find_diff_file(&config.local_archive, name))
+        .and_then(|diff| fs::metadata(diff).ok())
+        .map_or(0, |metadata| metadata.len());
+    let snapshot_count = count_timestamp_named_folders(&config.local_archive, &config.naming()).unwrap_or(0);
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, render_metrics(report, batch_file_bytes, snapshot_count)).context(format!("writing {temp:?}"))?;
+    fs::rename(&temp, path).context(format!("renaming {temp:?} to {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::archive::ArchiveOutcome;
+    use crate::syncer_util::{ChangeList, FsEntity};
+
+    /// Samples of a text exposition, checking that each has a valid name and value and is preceded by its
+    /// `# HELP` and `# TYPE` lines.
+    fn parse_exposition(text: &str) -> BTreeMap<String, f64> {
+        let is_name = |name: &str| !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':');
+        let mut samples = BTreeMap::new();
+        let mut described = Vec::new();
+        for line in text.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let (kind, rest) = comment.split_once(' ').unwrap();
+                let (name, value) = rest.split_once(' ').unwrap();
+                assert!(is_name(name), "{line}");
+                match kind {
+                    "HELP" => assert!(!value.is_empty(), "{line}"),
+                    "TYPE" => assert!(["counter", "gauge", "histogram", "summary", "untyped"].contains(&value), "{line}"),
+                    _ => panic!("unexpected comment {line}")
+                }
+                described.push((name.to_owned(), kind.to_owned()));
+            } else {
+                let (name, value) = line.split_once(' ').unwrap();
+                assert!(is_name(name), "{line}");
+                assert!(described.contains(&(name.to_owned(), "HELP".to_owned())), "{name} has no HELP");
+                assert!(described.contains(&(name.to_owned(), "TYPE".to_owned())), "{name} has no TYPE");
+                assert!(samples.insert(name.to_owned(), value.parse::<f64>().unwrap()).is_none(), "{name} repeated");
+            }
+        }
+        assert!(text.ends_with('\n'));
+        samples
+    }
+
+    #[test]
+    fn successful_run_metrics() {
+        let changes = ChangeList {
+            deleted: Vec::new(),
+            changed: vec![FsEntity::File("a".into()), FsEntity::File("b".into())],
+            created: Vec::new(),
+            moved: Vec::new(),
+        };
+        let report = ArchiveReport {
+            outcome: ArchiveOutcome::Created,
+            snapshot: Some("2024-01-01_00-00-00".to_owned()),
+            snapshot_path: None,
+            changes: Some(changes),
+            started: Local::now(),
+            finished: Local::now(),
+        };
+        let samples = parse_exposition(&render_metrics(Some(&report), 4096, 7));
+        assert_eq!(samples.len(), 5);
+        assert_eq!(samples["vhbarchsyn_last_run_success"], 1.0);
+        assert_eq!(samples["vhbarchsyn_changed_files"], 2.0);
+        assert_eq!(samples["vhbarchsyn_batch_file_bytes"], 4096.0);
+        assert_eq!(samples["vhbarchsyn_snapshot_count"], 7.0);
+        assert!(samples["vhbarchsyn_last_run_timestamp"] > 0.0);
+    }
+
+    #[test]
+    fn failed_run_metrics() {
+        let samples = parse_exposition(&render_metrics(None, 0, 3));
+        assert_eq!(samples["vhbarchsyn_last_run_success"], 0.0);
+        assert_eq!(samples["vhbarchsyn_changed_files"], 0.0);
+        assert_eq!(samples["vhbarchsyn_batch_file_bytes"], 0.0);
+    }
+}
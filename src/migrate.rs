@@ -0,0 +1,164 @@
+//! Renaming snapshots and their sidecars from one `date_format` to another.
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use tracing::{info, warn};
+use crate::syncer_util::{check_folder_names, sidecar_path, FolderName, SnapshotNaming, SIDECAR_EXTS};
+
+/// One snapshot to rename, names relative to `local_archive`.
+#[derive(Debug, Clone)]
+pub struct Rename {
+    pub from: String,
+    pub to: String,
+}
+
+/// Renames that move every snapshot named with date format `from` to date format `to`, prefix, suffix and layout
+/// stay as configured. Fails if a folder with the prefix and suffix doesn't parse with `from`, unless
+/// `skip_unparseable`, and if a new name is taken or given to two snapshots.
+pub fn plan_migration(local_archive: &Path, naming: &SnapshotNaming, from: &str, to: &str, skip_unparseable: bool) -> Result<Vec<Rename>> {
+    let from_naming = SnapshotNaming { date_format: from.to_owned(), legacy_date_formats: vec![], ..naming.clone() };
+    let to_naming = SnapshotNaming { date_format: to.to_owned(), legacy_date_formats: vec![], ..naming.clone() };
+    to_naming.validate().context(format!("invalid --to {to:?}"))?;
+
+    let mut renames = Vec::new();
+    let mut unparseable = Vec::new();
+    for (name, kind) in check_folder_names(local_archive, &from_naming)? {
+        match kind {
+            FolderName::Current => {
+                let timestamp = from_naming.parse(&name).ok_or(anyhow!("{name:?} doesn't parse with {from:?}"))?;
+                let new_name = to_naming.format(&timestamp);
+                if new_name != name {
+                    renames.push(Rename { from: name, to: new_name });
+                }
+            }
+            FolderName::Unparseable => unparseable.push(name),
+            _ => {}
+        }
+    }
+    if !unparseable.is_empty() {
+        if !skip_unparseable {
+            return Err(anyhow!("{} folders don't parse with {from:?}: {unparseable:?}, use --skip-unparseable to leave them as they are", unparseable.len()));
+        }
+        warn!("leaving {} folders that don't parse with {from:?} as they are: {unparseable:?}", unparseable.len());
+    }
+
+    let mut taken = HashSet::new();
+    for rename in &renames {
+        if !taken.insert(rename.to.as_str()) {
+            return Err(anyhow!("more than one snapshot would be renamed to {}", rename.to));
+        }
+        let existing = std::iter::once(local_archive.join(&rename.to))
+            .chain(SIDECAR_EXTS.iter().map(|ext| sidecar_path(local_archive, &rename.to, ext)))
+            .find(|path| path.exists());
+        if let Some(existing) = existing {
+            return Err(anyhow!("renaming {} to {} would collide with {existing:?}", rename.from, rename.to));
+        }
+    }
+    Ok(renames)
+}
+
+/// Renames the snapshot folders and their sidecars as planned by `plan_migration`.
+pub fn migrate_snapshots(local_archive: &Path, renames: &[Rename]) -> Result<()> {
+    for Rename { from, to } in renames {
+        let target = local_archive.join(to);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).context(format!("creating {parent:?}"))?;
+        }
+        fs::rename(local_archive.join(from), &target).context(format!("renaming {from} to {to}"))?;
+        for ext in SIDECAR_EXTS {
+            let sidecar = sidecar_path(local_archive, from, ext);
+            if sidecar.exists() {
+                fs::rename(&sidecar, sidecar_path(local_archive, to, ext)).context(format!("renaming {sidecar:?}"))?;
+            }
+        }
+        info!("renamed {from} to {to}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Duration, Local, Timelike};
+    use crate::syncer_util::{CHANGES_EXT, DIFF_EXT};
+    use crate::test_support::naming;
+
+    const FROM: &str = "%b%d_%Y_%H%M%S%z";
+    const TO: &str = "%Y-%m-%d_%H%M%S%z";
+
+    /// Snapshot folder named with `format` and a `.diff` sidecar, returns its name.
+    fn snapshot(archive: &Path, format: &str, timestamp: DateTime<Local>) -> String {
+        let naming = SnapshotNaming { date_format: format.to_owned(), ..naming() };
+        let name = naming.format(&timestamp);
+        fs::create_dir(archive.join(&name)).unwrap();
+        fs::write(sidecar_path(archive, &name, DIFF_EXT), "batch").unwrap();
+        name
+    }
+
+    #[test]
+    fn snapshots_and_sidecars_are_renamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path();
+        let timestamps = [Local::now() - Duration::days(2), Local::now() - Duration::days(1)];
+        let old: Vec<String> = timestamps.iter().map(|timestamp| snapshot(archive, FROM, *timestamp)).collect();
+        fs::write(sidecar_path(archive, &old[1], CHANGES_EXT), "{}").unwrap();
+        fs::create_dir(archive.join(".cas")).unwrap();
+
+        let renames = plan_migration(archive, &naming(), FROM, TO, false).unwrap();
+        assert_eq!(renames.len(), 2);
+        // planning alone, as --dry-run does, changes nothing
+        assert!(archive.join(&old[0]).is_dir());
+
+        migrate_snapshots(archive, &renames).unwrap();
+        let to_naming = SnapshotNaming { date_format: TO.to_owned(), ..naming() };
+        for (name, timestamp) in old.iter().zip(timestamps) {
+            let new = to_naming.format(&timestamp);
+            assert!(!archive.join(name).exists());
+            assert!(!sidecar_path(archive, name, DIFF_EXT).exists());
+            assert!(archive.join(&new).is_dir());
+            assert!(sidecar_path(archive, &new, DIFF_EXT).exists());
+        }
+        assert!(sidecar_path(archive, &to_naming.format(&timestamps[1]), CHANGES_EXT).exists());
+        assert!(plan_migration(archive, &naming(), TO, TO, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn a_taken_name_aborts_the_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path();
+        let timestamp = Local::now() - Duration::days(1);
+        let old = snapshot(archive, FROM, timestamp);
+        let to_naming = SnapshotNaming { date_format: TO.to_owned(), ..naming() };
+        fs::write(sidecar_path(archive, &to_naming.format(&timestamp), CHANGES_EXT), "{}").unwrap();
+        let e = plan_migration(archive, &naming(), FROM, TO, false).unwrap_err();
+        assert!(e.to_string().contains("would collide"), "{e}");
+
+        assert!(archive.join(&old).is_dir());
+    }
+
+    #[test]
+    fn two_snapshots_renamed_to_the_same_name_abort_the_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path();
+        // dropping the seconds gives two snapshots of the same minute the same name
+        let minute = (Local::now() - Duration::days(1)).with_second(0).unwrap();
+        snapshot(archive, FROM, minute + Duration::seconds(10));
+        snapshot(archive, FROM, minute + Duration::seconds(20));
+        let e = plan_migration(archive, &naming(), FROM, "%Y-%m-%d_%H%M%z", false).unwrap_err();
+        assert!(e.to_string().contains("more than one snapshot"), "{e}");
+    }
+
+    #[test]
+    fn unparseable_folders_abort_unless_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path();
+        let old = snapshot(archive, FROM, Local::now() - Duration::days(1));
+        fs::create_dir(archive.join("not-a-timestamp")).unwrap();
+        let e = plan_migration(archive, &naming(), FROM, TO, false).unwrap_err();
+        assert!(e.to_string().contains("--skip-unparseable"), "{e}");
+        let renames = plan_migration(archive, &naming(), FROM, TO, true).unwrap();
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].from, old);
+    }
+}
@@ -0,0 +1,102 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Context, Result};
+use tracing::info;
+use crate::config::{Config, MirrorConfig};
+use crate::syncer_util::{rsync_transfer, sidecar_path, snapshot_file_name, timestamp_named_folders, RsyncDirection, SnapshotNaming, MIRRORED_EXT};
+
+/// Snapshots without a `.mirrored` marker, oldest first.
+pub fn unmirrored_snapshots(local_archive: &Path, naming: &SnapshotNaming) -> Result<Vec<String>> {
+    Ok(timestamp_named_folders(local_archive, naming)?.into_iter()
+        .map(|(_, name)| name)
+        .filter(|name| !sidecar_path(local_archive, name, MIRRORED_EXT).exists())
+        .collect())
+}
+
+/// rsync filter rules transferring only snapshot `name` and its sidecar files.
+fn mirror_filter(name: &str) -> Vec<String> {
+    // the date folders of the hierarchical layout have to be included on the way down
+    let mut filter: Vec<String> = name.match_indices('/').map(|(i, _)| format!("--include=/{}/", &name[..i])).collect();
+    filter.extend([
+        format!("--include=/{name}/"),
+        format!("--include=/{name}/**"),
+        format!("--include=/{}.*", snapshot_file_name(name)),
+        "--exclude=*".to_owned(),
+    ]);
+    filter
+}
+
+/// Copies every snapshot that wasn't mirrored yet together with its sidecar files and marks it as mirrored.
+/// Snapshots are never removed from the mirror, fast-forwarded and pruned ones stay there.
+/// Returns the number of mirrored snapshots.
+pub fn mirror_snapshots(config: &Config, mirror: &MirrorConfig) -> Result<usize> {
+    let local_archive = &config.local_archive;
+    let pending = unmirrored_snapshots(local_archive, &config.naming())?;
+    if pending.is_empty() {
+        return Ok(0);
+    }
+    // snapshots are mirrored as they are, the working dir excludes don't apply
+    let rsync_options = config.rsync_options();
+    for name in &pending {
+        info!("mirroring {name}");
+        let direction = match (&mirror.path, &mirror.ssh, &mirror.daemon) {
+            (Some(path), _, _) => RsyncDirection::LocalToLocal { from: local_archive.clone(), to: path.clone() },
+            (None, Some(ssh), _) => RsyncDirection::LocalToRemote { from: local_archive.clone(), to: ssh.clone() },
+            (None, None, Some(daemon)) => RsyncDirection::LocalToDaemon { from: local_archive.clone(), to: daemon.clone() },
+            (None, None, None) => return Err(anyhow!("[mirror] has neither path, ssh nor daemon")),
+        };
+        let extra_args: Vec<OsString> = mirror_filter(name).into_iter().map(OsString::from).collect();
+        rsync_transfer(direction, &[], &extra_args, &rsync_options).context(format!("mirroring {name}"))?;
+        fs::write(sidecar_path(local_archive, name, MIRRORED_EXT), "")?;
+    }
+    Ok(pending.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{naming, snapshot_name};
+
+    #[test]
+    fn only_snapshots_without_a_marker_are_unmirrored() {
+        let dir = tempfile::tempdir().unwrap();
+        let naming = naming();
+        let names: Vec<String> = [3, 2, 1].into_iter().map(|days| snapshot_name(&naming, days)).collect();
+        for name in &names {
+            fs::create_dir(dir.path().join(name)).unwrap();
+        }
+        fs::create_dir(dir.path().join("not-a-snapshot")).unwrap();
+        fs::write(sidecar_path(dir.path(), &names[1], MIRRORED_EXT), "").unwrap();
+        assert_eq!(unmirrored_snapshots(dir.path(), &naming).unwrap(), [names[0].clone(), names[2].clone()]);
+
+        for name in &names {
+            fs::write(sidecar_path(dir.path(), name, MIRRORED_EXT), "").unwrap();
+        }
+        assert!(unmirrored_snapshots(dir.path(), &naming).unwrap().is_empty());
+    }
+
+    #[test]
+    fn filter_includes_the_snapshot_and_its_sidecars() {
+        assert_eq!(mirror_filter("2024-05-01_10-00-00+0200"), [
+            "--include=/2024-05-01_10-00-00+0200/",
+            "--include=/2024-05-01_10-00-00+0200/**",
+            "--include=/2024-05-01_10-00-00+0200.*",
+            "--exclude=*",
+        ]);
+    }
+
+    #[test]
+    fn filter_includes_the_date_folders_of_the_hierarchical_layout() {
+        // sidecars stay directly in the archive
+        assert_eq!(mirror_filter("2024/05/01/2024-05-01_10-00-00+0200"), [
+            "--include=/2024/",
+            "--include=/2024/05/",
+            "--include=/2024/05/01/",
+            "--include=/2024/05/01/2024-05-01_10-00-00+0200/",
+            "--include=/2024/05/01/2024-05-01_10-00-00+0200/**",
+            "--include=/2024-05-01_10-00-00+0200.*",
+            "--exclude=*",
+        ]);
+    }
+}
@@ -0,0 +1,94 @@
+//! Values that must never show up in logs, e.g. credentials in URLs. They are scrubbed from everything the log
+//! writer and the `.rsynclog` sidecar receive.
+use std::io::{self, Write};
+use std::sync::RwLock;
+use tracing_subscriber::fmt::MakeWriter;
+
+const REDACTED: &str = "***";
+
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Adds values to scrub from now on, empty ones are ignored.
+pub fn register_secrets(secrets: impl IntoIterator<Item = String>) {
+    if let Ok(mut registered) = SECRETS.write() {
+        for secret in secrets {
+            if !secret.is_empty() && !registered.contains(&secret) {
+                registered.push(secret);
+            }
+        }
+        // longer values first, in case one contains another
+        registered.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    }
+}
+
+/// `text` with every registered secret replaced by `***`.
+pub fn redact(text: &str) -> String {
+    let mut text = text.to_owned();
+    if let Ok(secrets) = SECRETS.read() {
+        for secret in secrets.iter() {
+            if text.contains(secret.as_str()) {
+                text = text.replace(secret.as_str(), REDACTED);
+            }
+        }
+    }
+    text
+}
+
+/// Log writer redacting everything written to `W`. Log events are written in one piece, so secrets are not split
+/// across writes.
+pub struct RedactingWriter<W>(W);
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_all(redact(&String::from_utf8_lossy(buf)).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Wraps the writers made by `M` in `RedactingWriter`s, for `FmtSubscriber::builder().with_writer`.
+pub struct RedactingMakeWriter<M>(pub M);
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingMakeWriter<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(self.0.make_writer())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // secrets are process wide, so every test registers its own values
+
+    #[test]
+    fn registered_secrets_are_replaced() {
+        assert_eq!(redact("user:s3cret-replaced@host"), "user:s3cret-replaced@host");
+        register_secrets(["s3cret-replaced".to_owned(), String::new()]);
+        assert_eq!(redact("user:s3cret-replaced@host s3cret-replaced"), "user:***@host ***");
+        assert_eq!(redact("nothing to hide"), "nothing to hide");
+    }
+
+    #[test]
+    fn a_secret_containing_another_is_replaced_whole() {
+        // registered shorter first, replacing it first would leave `***-suffix` behind
+        register_secrets(["overlap-token".to_owned()]);
+        register_secrets(["overlap-token-suffix".to_owned()]);
+        assert_eq!(redact("a=overlap-token-suffix b=overlap-token"), "a=*** b=***");
+    }
+
+    #[test]
+    fn writer_redacts_what_it_forwards() {
+        register_secrets(["writer-secret".to_owned()]);
+        let mut out = Vec::new();
+        let mut writer = RedactingWriter(&mut out);
+        writer.write_all(b"connecting with writer-secret\n").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "connecting with ***\n");
+    }
+}
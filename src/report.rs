@@ -0,0 +1,167 @@
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use chrono::{DateTime, FixedOffset, Local};
+use comfy_table::{presets, Cell, Color, ContentArrangement, Table};
+use crate::syncer_util::{sidecar_path, ChangeList, CHANGES_EXT};
+
+/// How the reporting commands (`list`, `diff`, `stats`) print their tables.
+pub struct Output {
+    /// Tab separated values without a header, for scripts
+    pub plain: bool,
+    pub color: bool,
+}
+
+/// One table cell, `color` is ignored in plain or colorless output.
+pub struct Field {
+    pub text: String,
+    pub color: Option<Color>,
+}
+
+impl Field {
+    pub fn new(text: impl Into<String>) -> Self {
+        Field { text: text.into(), color: None }
+    }
+
+    pub fn colored(text: impl Into<String>, color: Color) -> Self {
+        Field { text: text.into(), color: Some(color) }
+    }
+}
+
+impl Output {
+    /// Colors are used only on a terminal and when `NO_COLOR` is not set.
+    pub fn new(plain: bool) -> Self {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+        Output { plain, color: !plain && !no_color && io::stdout().is_terminal() }
+    }
+
+    pub fn render(&self, header: &[&str], rows: Vec<Vec<Field>>) -> String {
+        if self.plain {
+            return rows.iter()
+                .map(|row| row.iter().map(|f| f.text.as_str()).collect::<Vec<_>>().join("\t") + "\n")
+                .collect();
+        }
+        let mut table = Table::new();
+        table.load_preset(presets::UTF8_FULL_CONDENSED)
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_header(header.to_vec());
+        if self.color {
+            table.enforce_styling();
+        } else {
+            table.force_no_tty();
+        }
+        for row in rows {
+            table.add_row(row.into_iter().map(|f| match (f.color, self.color) {
+                (Some(color), true) => Cell::new(f.text).fg(color),
+                _ => Cell::new(f.text),
+            }));
+        }
+        format!("{table}\n")
+    }
+
+    pub fn print(&self, header: &[&str], rows: Vec<Vec<Field>>) {
+        print!("{}", self.render(header, rows));
+    }
+}
+
+/// Snapshot timestamp in the local time zone, in a readable form.
+pub fn human_timestamp(timestamp: &DateTime<FixedOffset>) -> String {
+    timestamp.with_timezone(&Local).format("%a %d %b %Y %H:%M:%S").to_string()
+}
+
+/// Size in bytes with a binary unit, e.g. `1.5 GiB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Rows of a change list: kind and path.
+pub fn change_rows(changes: &ChangeList) -> Vec<Vec<Field>> {
+    let mut rows = Vec::new();
+    for entity in &changes.changed {
+        let kind = if changes.is_created(entity) {
+            Field::colored("created", Color::Green)
+        } else {
+            Field::colored("changed", Color::Yellow)
+        };
+        rows.push(vec![kind, Field::new(entity.display_path())]);
+    }
+    for (from, to) in &changes.moved {
+        rows.push(vec![Field::colored("moved", Color::Cyan), Field::new(format!("{} -> {}", from.display_path(), to.display()))]);
+    }
+    for entity in &changes.deleted {
+        rows.push(vec![Field::colored("deleted", Color::Red), Field::new(entity.display_path())]);
+    }
+    rows
+}
+
+/// Change list recorded next to a snapshot, None if there is none (initial snapshot, fast-forwarded or crashed run).
+pub fn read_change_list(local_archive: &Path, name: &str) -> Option<ChangeList> {
+    let contents = fs::read_to_string(sidecar_path(local_archive, name, CHANGES_EXT)).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::syncer_util::FsEntity;
+
+    fn texts(rows: &[Vec<Field>]) -> Vec<Vec<&str>> {
+        rows.iter().map(|row| row.iter().map(|field| field.text.as_str()).collect()).collect()
+    }
+
+    #[test]
+    fn sizes_use_binary_units() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1024), "1.0 KiB");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+        assert_eq!(human_size(u64::MAX), "16384.0 PiB");
+    }
+
+    #[test]
+    fn plain_output_is_tab_separated_without_a_header() {
+        let output = Output { plain: true, color: false };
+        let rows = vec![
+            vec![Field::colored("created", Color::Green), Field::new("new file.txt")],
+            vec![Field::new("deleted"), Field::new("old.txt")],
+        ];
+        assert_eq!(output.render(&["kind", "path"], rows), "created\tnew file.txt\ndeleted\told.txt\n");
+        assert_eq!(output.render(&["kind", "path"], Vec::new()), "");
+    }
+
+    #[test]
+    fn table_output_has_a_header_and_no_colors_when_disabled() {
+        let output = Output { plain: false, color: false };
+        let table = output.render(&["kind", "path"], vec![vec![Field::colored("created", Color::Green), Field::new("new.txt")]]);
+        assert!(table.contains("kind") && table.contains("new.txt"), "{table}");
+        assert!(!table.contains('\x1b'), "{table}");
+    }
+
+    #[test]
+    fn change_rows_list_each_kind() {
+        let changes = ChangeList {
+            deleted: vec![FsEntity::folder("olddir")],
+            changed: vec![FsEntity::file("new.txt"), FsEntity::file("modified.txt")],
+            created: vec![FsEntity::file("new.txt")],
+            moved: vec![(FsEntity::file("photo.jpg"), PathBuf::from("photos/photo.jpg"))],
+        };
+        assert_eq!(texts(&change_rows(&changes)), [
+            ["created", "new.txt"],
+            ["changed", "modified.txt"],
+            ["moved", "photo.jpg -> photos/photo.jpg"],
+            ["deleted", "olddir/"],
+        ]);
+    }
+}
@@ -0,0 +1,365 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, FixedOffset, Local};
+use tracing::info;
+use crate::config::Config;
+use crate::gc::split_sidecar_name;
+use crate::manifest::{build_manifest, compare_manifests, ExcludeMatcher};
+use crate::meta::read_meta;
+use crate::syncer_util::{find_diff_file, rsync_apply_diff, rsync_transfer, snapshot_file_name, timestamp_named_folders, with_plain_diff_file, RsyncDirection, DIFF_EXT, DIFF_ZST_EXT};
+use crate::util::is_empty_dir;
+
+/// What `restore` does with files already in a non-empty target folder.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Leave existing files as they are, only add the missing ones (rsync `--ignore-existing`)
+    Keep,
+    /// Make the folder match the snapshot, deleting files that are not in it, same as `--force`
+    Overwrite,
+    /// Rename files that would be overwritten to `<name>.bak-<timestamp>` first (rsync `--backup`)
+    Rename,
+}
+
+/// Copies snapshot `name` into `into`. Hard links into the dedup pool become regular files.
+/// Refuses to write into a non-empty folder unless `on_conflict` is set. With `Overwrite` files that are not in
+/// the snapshot are deleted from `into` (excluded ones are kept).
+pub fn restore_snapshot(config: &Config, name: &str, into: &Path, on_conflict: Option<OnConflict>) -> Result<()> {
+    if config.naming().parse(name).is_none() {
+        return Err(anyhow!("{name:?} is not a snapshot name"));
+    }
+    let snapshot = config.local_archive.join(name);
+    if !snapshot.is_dir() {
+        return Err(anyhow!("snapshot {snapshot:?} doesn't exist"));
+    }
+    let mut extra_args = Vec::new();
+    if !is_empty_dir(into).context(format!("reading {into:?}"))? {
+        match on_conflict {
+            None => return Err(anyhow!("{into:?} is not empty, use --force to overwrite it or pick an --on-conflict mode")),
+            Some(OnConflict::Keep) => extra_args.push(OsString::from("--ignore-existing")),
+            Some(OnConflict::Overwrite) => extra_args.push(OsString::from("--delete")),
+            Some(OnConflict::Rename) => {
+                extra_args.push(OsString::from("--backup"));
+                extra_args.push(OsString::from(format!("--suffix=.bak-{}", Local::now().format("%Y%m%d%H%M%S"))));
+            }
+        }
+    }
+    info!("restoring {snapshot:?} into {into:?}");
+    rsync_transfer(RsyncDirection::LocalToLocal {
+        from: snapshot,
+        to: into.to_path_buf()
+    }, &config.exclude, &extra_args, &config.rsync_options())?;
+    Ok(())
+}
+
+/// Compares the restored files in `into` with snapshot `name` by content, excluded paths are skipped on both sides.
+/// With `--force`, files that were in `into` before and are excluded are kept, so they don't count as unexpected.
+pub fn verify_restore(config: &Config, name: &str, into: &Path) -> Result<()> {
+    let excludes = ExcludeMatcher::load(&config.exclude)?;
+    let snapshot = config.local_archive.join(name);
+    info!("verifying {into:?} against {snapshot:?}");
+    let mismatches = compare_manifests(&build_manifest(&snapshot, &excludes)?, &build_manifest(into, &excludes)?);
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    for mismatch in &mismatches {
+        println!("    {mismatch}");
+    }
+    Err(anyhow!("{into:?} doesn't match snapshot {name}"))
+}
+
+/// Snapshots after `from` up to and including `to`, oldest first, found by following the `parent` links of their
+/// `.meta.json` back from `to`. None if a snapshot on the way has no parent recorded, e.g. one made before they
+/// were, then the order has to be inferred from the timestamps.
+fn parent_chain(config: &Config, from: &str, to: &str) -> Result<Option<Vec<String>>> {
+    let naming = config.naming();
+    let from_timestamp = naming.parse(from).ok_or(anyhow!("{from:?} is not a snapshot name"))?;
+    let mut chain = vec![to.to_owned()];
+    loop {
+        let current = chain.last().unwrap();
+        let parent = match read_meta(&config.local_archive, current)?.and_then(|meta| meta.parent) {
+            Some(parent) => parent,
+            None => return Ok(None)
+        };
+        if parent == from {
+            chain.reverse();
+            return Ok(Some(chain));
+        }
+        let parent_timestamp = naming.parse(&parent).ok_or(anyhow!("{current} records parent {parent:?}, which is not a snapshot name"))?;
+        if naming.parse(current).is_some_and(|timestamp| parent_timestamp >= timestamp) {
+            return Err(anyhow!("{current} records parent {parent}, which is not older than it"));
+        }
+        if parent_timestamp < from_timestamp {
+            return Err(anyhow!("{to} doesn't descend from {from}, its history goes through {parent} instead"));
+        }
+        chain.push(parent);
+    }
+}
+
+/// Batch files recorded after `from` up to and including `to`, oldest first. Parent links are followed when
+/// recorded, otherwise every snapshot folder in that range must have its batch, and so must `to`, otherwise the
+/// chain is broken.
+fn diff_chain(config: &Config, from_name: &str, from: DateTime<FixedOffset>, to: DateTime<FixedOffset>, to_name: &str) -> Result<Vec<PathBuf>> {
+    if let Some(chain) = parent_chain(config, from_name, to_name)? {
+        return chain.iter()
+            .map(|name| find_diff_file(&config.local_archive, name)
+                .ok_or(anyhow!("batch file {}.{DIFF_EXT} is missing, the chain from the base snapshot is broken", snapshot_file_name(name))))
+            .collect();
+    }
+    let naming = config.naming();
+    let mut diffs: Vec<(DateTime<FixedOffset>, String, PathBuf)> = Vec::new();
+    for entry in fs::read_dir(&config.local_archive).context("unable to read local archive")? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        if let Some((name, DIFF_EXT | DIFF_ZST_EXT)) = file_name.to_str().and_then(split_sidecar_name) {
+            if let Some(timestamp) = naming.parse(name) {
+                if timestamp > from && timestamp <= to {
+                    diffs.push((timestamp, name.to_owned(), entry.path()));
+                }
+            }
+        }
+    }
+    diffs.sort();
+
+    let mut required: Vec<String> = timestamp_named_folders(&config.local_archive, &naming)?.into_iter()
+        .filter(|(timestamp, _)| *timestamp > from && *timestamp <= to)
+        .map(|(_, name)| name)
+        .collect();
+    if !required.iter().any(|name| name == to_name) {
+        required.push(to_name.to_owned());
+    }
+    for name in &required {
+        if !diffs.iter().any(|(_, diff_name, _)| diff_name == snapshot_file_name(name)) {
+            return Err(anyhow!("batch file {}.{DIFF_EXT} is missing, the chain from the base snapshot is broken", snapshot_file_name(name)));
+        }
+    }
+    Ok(diffs.into_iter().map(|(_, _, path)| path).collect())
+}
+
+/// Restores snapshot `from` into `into` and replays the recorded batch files on top of it up to snapshot `to`.
+/// `to` doesn't have to exist anymore, e.g. when it was fast-forwarded, as long as its batch file does.
+pub fn replay_snapshots(config: &Config, from: &str, to: &str, into: &Path) -> Result<()> {
+    let naming = config.naming();
+    let from_timestamp = naming.parse(from).ok_or(anyhow!("{from:?} is not a snapshot name"))?;
+    let to_timestamp = naming.parse(to).ok_or(anyhow!("{to:?} is not a snapshot name"))?;
+    if to_timestamp <= from_timestamp {
+        return Err(anyhow!("{to} is not newer than {from}"));
+    }
+    let diffs = diff_chain(config, from, from_timestamp, to_timestamp, to)?;
+    restore_snapshot(config, from, into, None)?;
+    let rsync_options = config.rsync_options();
+    for diff in &diffs {
+        info!("replaying {diff:?}");
+        with_plain_diff_file(diff, |diff| rsync_apply_diff(into, diff, &config.exclude, &[], &rsync_options))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use crate::syncer_util::{compress_diff_file, rsync_extract_diff, sidecar_path, META_EXT};
+    use crate::test_support::{rsync_available, test_config};
+
+    /// Snapshot taken `days` ago holding `files`.
+    fn snapshot(config: &Config, days: i64, files: &[(&str, &str)]) -> String {
+        let name = config.naming().format(&(Local::now() - Duration::days(days)));
+        let path = config.local_archive.join(&name);
+        fs::create_dir(&path).unwrap();
+        for (file, contents) in files {
+            fs::write(path.join(file), contents).unwrap();
+        }
+        name
+    }
+
+    #[test]
+    fn non_empty_target_needs_a_conflict_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let name = snapshot(&config, 1, &[("file.txt", "archived")]);
+        let into = dir.path().join("into");
+        fs::create_dir(&into).unwrap();
+        fs::write(into.join("local.txt"), "local").unwrap();
+        let e = restore_snapshot(&config, &name, &into, None).unwrap_err();
+        assert!(e.to_string().contains("not empty"), "{e}");
+        assert!(restore_snapshot(&config, "not a snapshot", &into, Some(OnConflict::Overwrite)).is_err());
+    }
+
+    #[test]
+    fn conflict_modes_keep_overwrite_or_rename() {
+        if !rsync_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let name = snapshot(&config, 1, &[("file.txt", "archived")]);
+        let prepare = |mode: &str| {
+            let into = dir.path().join(mode);
+            fs::create_dir(&into).unwrap();
+            fs::write(into.join("file.txt"), "local").unwrap();
+            fs::write(into.join("extra.txt"), "extra").unwrap();
+            into
+        };
+
+        let into = prepare("keep");
+        restore_snapshot(&config, &name, &into, Some(OnConflict::Keep)).unwrap();
+        assert_eq!(fs::read_to_string(into.join("file.txt")).unwrap(), "local");
+        assert!(into.join("extra.txt").exists());
+
+        let into = prepare("overwrite");
+        restore_snapshot(&config, &name, &into, Some(OnConflict::Overwrite)).unwrap();
+        assert_eq!(fs::read_to_string(into.join("file.txt")).unwrap(), "archived");
+        assert!(!into.join("extra.txt").exists());
+        verify_restore(&config, &name, &into).unwrap();
+
+        let into = prepare("rename");
+        restore_snapshot(&config, &name, &into, Some(OnConflict::Rename)).unwrap();
+        assert_eq!(fs::read_to_string(into.join("file.txt")).unwrap(), "archived");
+        let backups: Vec<_> = fs::read_dir(&into).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("file.txt.bak-"))
+            .collect();
+        assert_eq!(backups.len(), 1, "{backups:?}");
+        assert_eq!(fs::read_to_string(into.join(&backups[0])).unwrap(), "local");
+    }
+
+    #[test]
+    fn verify_restore_compares_contents_outside_the_excludes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        fs::write(&config.exclude[0], "*.tmp\n").unwrap();
+        let name = snapshot(&config, 1, &[("file.txt", "archived"), ("scratch.tmp", "scratch")]);
+        let into = dir.path().join("into");
+        fs::create_dir(&into).unwrap();
+        fs::write(into.join("file.txt"), "archived").unwrap();
+        fs::write(into.join("other.tmp"), "excluded").unwrap();
+        verify_restore(&config, &name, &into).unwrap();
+
+        fs::write(into.join("file.txt"), "changed").unwrap();
+        assert!(verify_restore(&config, &name, &into).is_err());
+        fs::write(into.join("file.txt"), "archived").unwrap();
+        fs::write(into.join("unexpected.txt"), "").unwrap();
+        assert!(verify_restore(&config, &name, &into).is_err());
+        fs::remove_file(into.join("unexpected.txt")).unwrap();
+        fs::remove_file(into.join("file.txt")).unwrap();
+        assert!(verify_restore(&config, &name, &into).is_err());
+    }
+
+    fn write_parent(config: &Config, name: &str, parent: &str) {
+        let meta = serde_json::json!({
+            "tool_version": "0.1.0", "hostname": "host", "started": "", "finished": "", "commands": [], "parent": parent,
+        });
+        fs::write(sidecar_path(&config.local_archive, name, META_EXT), meta.to_string()).unwrap();
+    }
+
+    fn write_diff(config: &Config, name: &str) -> PathBuf {
+        let path = sidecar_path(&config.local_archive, name, DIFF_EXT);
+        fs::write(&path, "batch").unwrap();
+        path
+    }
+
+    fn chain(config: &Config, from: &str, to: &str) -> Result<Vec<PathBuf>> {
+        let naming = config.naming();
+        diff_chain(config, from, naming.parse(from).unwrap(), naming.parse(to).unwrap(), to)
+    }
+
+    #[test]
+    fn diff_chain_follows_timestamps_without_parents() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let names: Vec<_> = (1..=4).rev().map(|days| snapshot(&config, days, &[])).collect();
+        let diffs: Vec<_> = names.iter().map(|name| write_diff(&config, name)).collect();
+        assert_eq!(chain(&config, &names[0], &names[3]).unwrap(), diffs[1..]);
+        assert_eq!(chain(&config, &names[1], &names[2]).unwrap(), diffs[2..3]);
+
+        fs::remove_file(&diffs[2]).unwrap();
+        let e = chain(&config, &names[0], &names[3]).unwrap_err();
+        assert!(e.to_string().contains("is missing"), "{e}");
+    }
+
+    #[test]
+    fn diff_chain_follows_parent_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let names: Vec<_> = (1..=4).rev().map(|days| snapshot(&config, days, &[])).collect();
+        let diffs: Vec<_> = names.iter().map(|name| write_diff(&config, name)).collect();
+        // names[3] branched off names[1] with `archive --base`, names[2] is not part of its history
+        write_parent(&config, &names[1], &names[0]);
+        write_parent(&config, &names[2], &names[1]);
+        write_parent(&config, &names[3], &names[1]);
+        assert_eq!(parent_chain(&config, &names[0], &names[3]).unwrap().unwrap(), [names[1].clone(), names[3].clone()]);
+        assert_eq!(chain(&config, &names[0], &names[3]).unwrap(), [diffs[1].clone(), diffs[3].clone()]);
+
+        let e = parent_chain(&config, &names[2], &names[3]).unwrap_err();
+        assert!(e.to_string().contains("doesn't descend"), "{e}");
+        write_parent(&config, &names[2], &names[3]);
+        let e = parent_chain(&config, &names[0], &names[2]).unwrap_err();
+        assert!(e.to_string().contains("not older"), "{e}");
+    }
+
+    #[test]
+    fn missing_parent_falls_back_to_timestamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let names: Vec<_> = (1..=3).rev().map(|days| snapshot(&config, days, &[])).collect();
+        let diffs: Vec<_> = names.iter().map(|name| write_diff(&config, name)).collect();
+        // names[1] was made before parents were recorded
+        write_parent(&config, &names[2], &names[1]);
+        assert_eq!(parent_chain(&config, &names[0], &names[2]).unwrap(), None);
+        assert_eq!(chain(&config, &names[0], &names[2]).unwrap(), diffs[1..]);
+    }
+
+    /// Writes the batch file turning snapshot `prev` into `name`, as an archive run would have.
+    fn extract_diff(config: &Config, prev: &str, name: &str) {
+        let direction = RsyncDirection::LocalToLocal { from: config.local_archive.join(name), to: config.local_archive.join(prev) };
+        let diff = sidecar_path(&config.local_archive, name, DIFF_EXT);
+        rsync_extract_diff(direction, Some(&diff), &config.exclude, &config.rsync_options()).unwrap();
+    }
+
+    #[test]
+    fn replay_applies_each_batch_in_order() {
+        if !rsync_available() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let first = snapshot(&config, 3, &[("kept.txt", "kept"), ("deleted.txt", "deleted")]);
+        let second = snapshot(&config, 2, &[("kept.txt", "kept"), ("changed.txt", "v1")]);
+        let third = snapshot(&config, 1, &[("kept.txt", "kept"), ("changed.txt", "v2"), ("new.txt", "new")]);
+        extract_diff(&config, &first, &second);
+        extract_diff(&config, &second, &third);
+        // the snapshot folders are not needed, only the base and the batch files
+        fs::remove_dir_all(config.local_archive.join(&second)).unwrap();
+
+        let into = dir.path().join("into");
+        replay_snapshots(&config, &first, &third, &into).unwrap();
+        let mut files: Vec<_> = fs::read_dir(&into).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        files.sort();
+        assert_eq!(files, ["changed.txt", "kept.txt", "new.txt"]);
+        assert_eq!(fs::read_to_string(into.join("changed.txt")).unwrap(), "v2");
+
+        // compressed batch files replay to the same result
+        for name in [&second, &third] {
+            compress_diff_file(&sidecar_path(&config.local_archive, name, DIFF_EXT)).unwrap();
+        }
+        let compressed_into = dir.path().join("compressed");
+        replay_snapshots(&config, &first, &third, &compressed_into).unwrap();
+        let excludes = ExcludeMatcher::load(&[]).unwrap();
+        let manifests = [&into, &compressed_into].map(|dir| build_manifest(dir, &excludes).unwrap());
+        assert!(compare_manifests(&manifests[0], &manifests[1]).is_empty());
+    }
+
+    #[test]
+    fn replay_goes_forward_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path());
+        let older = snapshot(&config, 2, &[]);
+        let newer = snapshot(&config, 1, &[]);
+        let into = dir.path().join("into");
+        assert!(replay_snapshots(&config, &newer, &older, &into).is_err());
+        assert!(replay_snapshots(&config, &older, &older, &into).is_err());
+        assert!(!into.exists());
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, FixedOffset};
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use crate::meta::read_meta;
+use crate::syncer_util::{sidecar_path, timestamp_named_folders, SnapshotNaming, PIN_EXT};
+use crate::util::{for_each_path, Failures};
+
+/// Which snapshots `prune` keeps, a snapshot is kept if any rule keeps it.
+/// Without any rules nothing is removed. The latest snapshot, pinned and tagged ones are always kept.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Keep the N most recent snapshots
+    pub keep_last: Option<usize>,
+    /// Keep the most recent snapshot of each of the last N days that have snapshots
+    pub keep_daily: Option<usize>,
+    /// Keep snapshots whose note from `archive --note` matches one of these glob patterns, e.g. `"release*"`.
+    /// Like pins they only protect snapshots, they are not a rule of their own.
+    #[serde(default)]
+    pub keep_tagged: Vec<String>,
+}
+
+impl RetentionPolicy {
+    fn is_empty(&self) -> bool {
+        self.keep_last.is_none() && self.keep_daily.is_none()
+    }
+}
+
+/// Why a snapshot is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepReason {
+    /// The next archive run diffs against it
+    Latest,
+    KeepLast,
+    KeepDaily,
+    Pinned,
+    /// Its note matches `keep_tagged`
+    Tagged,
+    /// The policy has no rules, nothing is removed
+    NoRules,
+}
+
+impl Display for KeepReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeepReason::Latest => write!(f, "latest"),
+            KeepReason::KeepLast => write!(f, "keep_last"),
+            KeepReason::KeepDaily => write!(f, "keep_daily"),
+            KeepReason::Pinned => write!(f, "pinned"),
+            KeepReason::Tagged => write!(f, "keep_tagged"),
+            KeepReason::NoRules => write!(f, "no retention rules"),
+        }
+    }
+}
+
+/// Reasons to keep each snapshot, in the order of `snapshots` (oldest first). Snapshots without reasons are removed.
+pub fn classify(snapshots: &[(DateTime<FixedOffset>, String)], policy: &RetentionPolicy, pinned: &HashSet<String>, tagged: &HashSet<String>) -> Vec<(String, Vec<KeepReason>)> {
+    let mut reasons: Vec<Vec<KeepReason>> = vec![vec![]; snapshots.len()];
+    if policy.is_empty() {
+        reasons.iter_mut().for_each(|r| r.push(KeepReason::NoRules));
+    }
+    // indices into `snapshots`, newest first
+    let newest_first: Vec<usize> = (0..snapshots.len()).rev().collect();
+    if let Some(&latest) = newest_first.first() {
+        reasons[latest].push(KeepReason::Latest);
+    }
+    if let Some(n) = policy.keep_last {
+        newest_first.iter().take(n).for_each(|&i| reasons[i].push(KeepReason::KeepLast));
+    }
+    if let Some(n) = policy.keep_daily {
+        let mut days = HashSet::new();
+        for &i in &newest_first {
+            if days.len() < n && days.insert(snapshots[i].0.date_naive()) {
+                reasons[i].push(KeepReason::KeepDaily);
+            }
+        }
+    }
+    for (i, (_, name)) in snapshots.iter().enumerate() {
+        if pinned.contains(name) {
+            reasons[i].push(KeepReason::Pinned);
+        }
+        if tagged.contains(name) {
+            reasons[i].push(KeepReason::Tagged);
+        }
+    }
+    snapshots.iter().map(|(_, name)| name.clone()).zip(reasons).collect()
+}
+
+/// Names of the snapshots that `policy` doesn't keep, `snapshots` are sorted oldest first.
+pub fn select_to_delete(snapshots: &[(DateTime<FixedOffset>, String)], policy: &RetentionPolicy, pinned: &HashSet<String>, tagged: &HashSet<String>) -> Vec<String> {
+    classify(snapshots, policy, pinned, tagged).into_iter()
+        .filter(|(_, reasons)| reasons.is_empty())
+        .map(|(name, _)| name)
+        .collect()
+}
+
+pub fn is_pinned(local_archive: &Path, name: &str) -> bool {
+    sidecar_path(local_archive, name, PIN_EXT).exists()
+}
+
+/// Marks snapshot `name` so that `prune` never removes it.
+pub fn pin_snapshot(local_archive: &Path, naming: &SnapshotNaming, name: &str) -> Result<()> {
+    if naming.parse(name).is_none() || !local_archive.join(name).is_dir() {
+        return Err(anyhow!("there is no snapshot {name:?} in {local_archive:?}"));
+    }
+    let path = sidecar_path(local_archive, name, PIN_EXT);
+    fs::write(&path, b"").context(format!("writing {path:?}"))
+}
+
+/// `classify` applied to the snapshots in `local_archive`.
+pub fn retention_plan(local_archive: &Path, naming: &SnapshotNaming, policy: &RetentionPolicy) -> Result<Vec<(String, Vec<KeepReason>)>> {
+    let snapshots = timestamp_named_folders(local_archive, naming)?;
+    let pinned: HashSet<String> = snapshots.iter()
+        .filter(|(_, name)| is_pinned(local_archive, name))
+        .map(|(_, name)| name.clone())
+        .collect();
+    let tagged = tagged_snapshots(local_archive, &snapshots, &policy.keep_tagged)?;
+    Ok(classify(&snapshots, policy, &pinned, &tagged))
+}
+
+/// Snapshots whose note matches one of `patterns`. A `.meta.json` that can't be read is an error, the snapshot
+/// might be tagged.
+fn tagged_snapshots(local_archive: &Path, snapshots: &[(DateTime<FixedOffset>, String)], patterns: &[String]) -> Result<HashSet<String>> {
+    if patterns.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let patterns = patterns.iter()
+        .map(|pattern| Pattern::new(pattern).context(format!("invalid keep_tagged pattern {pattern:?}")))
+        .collect::<Result<Vec<_>>>()?;
+    let mut tagged = HashSet::new();
+    for (_, name) in snapshots {
+        let note = read_meta(local_archive, name)?.and_then(|meta| meta.note);
+        if note.is_some_and(|note| patterns.iter().any(|pattern| pattern.matches(&note))) {
+            tagged.insert(name.clone());
+        }
+    }
+    Ok(tagged)
+}
+
+/// Snapshot folders `policy` would remove. Their sidecars are left for `gc`.
+pub fn find_prunable(local_archive: &Path, naming: &SnapshotNaming, policy: &RetentionPolicy) -> Result<Vec<PathBuf>> {
+    Ok(retention_plan(local_archive, naming, policy)?.into_iter()
+        .filter(|(_, reasons)| reasons.is_empty())
+        .map(|(name, _)| local_archive.join(name))
+        .collect())
+}
+
+/// Returns the snapshots that couldn't be removed when `keep_going` is set.
+pub fn remove_snapshots(snapshots: &[PathBuf], keep_going: bool) -> Result<Failures> {
+    for_each_path(snapshots, keep_going, |path| {
+        info!("removing {path:?}");
+        fs::remove_dir_all(path).context(format!("removing {path:?}"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::SnapshotMeta;
+    use crate::syncer_util::META_EXT;
+    use crate::test_support::{naming, snapshot_name};
+
+    fn snapshots(local_archive: &Path, days: &[i64]) -> Vec<String> {
+        let naming = naming();
+        days.iter().map(|&days| {
+            let name = snapshot_name(&naming, days);
+            fs::create_dir(local_archive.join(&name)).unwrap();
+            name
+        }).collect()
+    }
+
+    fn set_note(local_archive: &Path, name: &str, note: &str) {
+        let meta = serde_json::json!({
+            "tool_version": "0.1.0", "hostname": "host", "started": "", "finished": "", "commands": [], "note": note,
+        });
+        let meta: SnapshotMeta = serde_json::from_value(meta).unwrap();
+        fs::write(sidecar_path(local_archive, name, META_EXT), serde_json::to_string(&meta).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn pinned_snapshot_survives_keep_last_1() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = snapshots(dir.path(), &[3, 2, 1]);
+        pin_snapshot(dir.path(), &naming(), &names[0]).unwrap();
+        let policy = RetentionPolicy { keep_last: Some(1), ..Default::default() };
+
+        let prunable = find_prunable(dir.path(), &naming(), &policy).unwrap();
+        assert_eq!(prunable, [dir.path().join(&names[1])]);
+        let plan = retention_plan(dir.path(), &naming(), &policy).unwrap();
+        assert_eq!(plan[0].1, [KeepReason::Pinned]);
+    }
+
+    #[test]
+    fn keep_tagged_keeps_snapshots_with_a_matching_note() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = snapshots(dir.path(), &[4, 3, 2, 1]);
+        set_note(dir.path(), &names[0], "release 1.0");
+        set_note(dir.path(), &names[1], "nightly");
+        let mut policy = RetentionPolicy { keep_last: Some(1), ..Default::default() };
+        assert_eq!(find_prunable(dir.path(), &naming(), &policy).unwrap().len(), 3);
+
+        policy.keep_tagged = vec!["release*".to_owned()];
+        let prunable = find_prunable(dir.path(), &naming(), &policy).unwrap();
+        assert_eq!(prunable, [dir.path().join(&names[1]), dir.path().join(&names[2])]);
+    }
+
+    #[test]
+    fn keep_tagged_fails_on_an_unreadable_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = snapshots(dir.path(), &[2, 1]);
+        fs::write(sidecar_path(dir.path(), &names[0], META_EXT), "{").unwrap();
+        let policy = RetentionPolicy { keep_last: Some(1), keep_daily: None, keep_tagged: vec!["*".to_owned()] };
+        assert!(find_prunable(dir.path(), &naming(), &policy).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! Snapshots stored in an S3-compatible bucket.
+//!
+//! Each snapshot is an object prefix `<prefix>/<snapshot>/`, the empty base snapshot holds a `.snapshot` marker
+//! so that it shows up in listings, sidecars are objects `<prefix>/<snapshot>.<ext>`. Copies are server side
+//! (CopyObject), then the files `extract_changes` found changed are uploaded and the deleted ones removed. As with
+//! `aws s3 sync`, a file counts as changed when its size differs or it was modified after its object was written.
+//! No rsync batch files are produced, moves are not detected, symlinks and empty folders are not stored and files
+//! are limited to 5 GB, as each is written with a single request. Excludes are matched with `ExcludeMatcher`,
+//! which covers the common subset of rsync patterns.
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::{anyhow, Context, Result};
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{Delete, ObjectIdentifier};
+use aws_sdk_s3::Client;
+use chrono::{DateTime, FixedOffset};
+use tokio::runtime::Runtime;
+use tracing::{debug, info, warn};
+use crate::backend::Backend;
+use crate::config::{Config, S3Config};
+use crate::manifest::ExcludeMatcher;
+use crate::syncer_util::{ChangeList, FsEntity};
+use crate::util::{path_to_str, walk_files};
+
+/// Object of the empty base snapshot, which has no files
+const SNAPSHOT_MARKER: &str = ".snapshot";
+/// DeleteObjects takes at most this many keys
+const DELETE_BATCH: usize = 1000;
+
+/// Relative path -> size and modification time of a file or object.
+type Listing = BTreeMap<PathBuf, (u64, SystemTime)>;
+
+/// Files found by `extract_changes`, relative to the working dir.
+struct PendingChanges {
+    upload: Vec<PathBuf>,
+    delete: Vec<PathBuf>,
+}
+
+pub struct S3Backend<'a> {
+    pub config: &'a Config,
+    pub s3: &'a S3Config,
+    client: Client,
+    runtime: Runtime,
+    /// Applied by `apply_changes`: the copied snapshot has new modification times, so it can't be compared with
+    /// the working dir again
+    pending: RefCell<Option<PendingChanges>>,
+}
+
+impl<'a> S3Backend<'a> {
+    /// Client for the bucket, credentials and region come from `profile` or the usual AWS environment.
+    pub fn new(config: &'a Config, s3: &'a S3Config) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("starting the S3 client runtime")?;
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(profile) = &s3.profile {
+            loader = loader.profile_name(profile);
+        }
+        if let Some(region) = &s3.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(endpoint_url) = &s3.endpoint_url {
+            loader = loader.endpoint_url(endpoint_url);
+        }
+        let sdk_config = runtime.block_on(loader.load());
+        // S3-compatible stores usually serve buckets as paths, not as subdomains
+        let s3_config = aws_sdk_s3::config::Builder::from(&sdk_config)
+            .force_path_style(s3.endpoint_url.is_some())
+            .build();
+        Ok(S3Backend { config, s3, client: Client::from_conf(s3_config), runtime, pending: RefCell::new(None) })
+    }
+
+    /// `name` below the configured prefix.
+    fn key(&self, name: &str) -> String {
+        let prefix = self.s3.prefix.trim_matches('/');
+        if prefix.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{prefix}/{name}")
+        }
+    }
+
+    fn object_key(&self, snapshot: &str, relative: &Path) -> Result<String> {
+        Ok(format!("{}/{}", self.key(snapshot), path_to_str(relative)?))
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("s3://{}/{key}", self.s3.bucket)
+    }
+
+    /// Snapshot timestamps and names
+    fn list(&self) -> Result<Vec<(DateTime<FixedOffset>, String)>> {
+        let prefix = self.key("");
+        let naming = self.config.naming();
+        self.runtime.block_on(async {
+            let mut pages = self.client.list_objects_v2()
+                .bucket(&self.s3.bucket)
+                .prefix(&prefix)
+                .delimiter("/")
+                .into_paginator()
+                .send();
+            let mut snapshots = Vec::new();
+            while let Some(page) = pages.next().await {
+                let page = page.context(format!("listing {}", self.url(&prefix)))?;
+                for common_prefix in page.common_prefixes() {
+                    let name = match common_prefix.prefix().and_then(|p| p.strip_prefix(prefix.as_str())) {
+                        Some(name) => name.trim_end_matches('/'),
+                        None => continue
+                    };
+                    if let Some(timestamp) = naming.parse(name) {
+                        snapshots.push((timestamp, name.to_owned()));
+                    }
+                }
+            }
+            Ok(snapshots)
+        })
+    }
+
+    /// Objects of snapshot `name` relative to it, the marker of an empty base snapshot included.
+    fn list_objects(&self, name: &str) -> Result<Listing> {
+        let prefix = self.key(name) + "/";
+        self.runtime.block_on(async {
+            let mut pages = self.client.list_objects_v2()
+                .bucket(&self.s3.bucket)
+                .prefix(&prefix)
+                .into_paginator()
+                .send();
+            let mut objects = Listing::new();
+            while let Some(page) = pages.next().await {
+                let page = page.context(format!("listing {}", self.url(&prefix)))?;
+                for object in page.contents() {
+                    let (Some(key), Some(size), Some(written)) = (object.key(), object.size(), object.last_modified()) else {
+                        continue
+                    };
+                    let relative = match key.strip_prefix(prefix.as_str()) {
+                        Some(relative) => relative,
+                        None => continue
+                    };
+                    let written = SystemTime::try_from(*written).context(format!("modification time of {}", self.url(key)))?;
+                    objects.insert(PathBuf::from(relative), (size as u64, written));
+                }
+            }
+            Ok(objects)
+        })
+    }
+
+    /// Files in the working dir that are not excluded.
+    fn list_working_dir(&self, excludes: &ExcludeMatcher) -> Result<Listing> {
+        let source = self.config.source_dir();
+        let mut files = Listing::new();
+        for file in walk_files(source).context(format!("walking {source:?}"))? {
+            let relative = file.strip_prefix(source)?.to_path_buf();
+            if excludes.is_excluded(&relative) {
+                continue;
+            }
+            let metadata = fs::metadata(&file).context(format!("reading metadata of {file:?}"))?;
+            files.insert(relative, (metadata.len(), metadata.modified()?));
+        }
+        Ok(files)
+    }
+
+    fn put_object(&self, key: &str, body: ByteStream) -> Result<()> {
+        self.runtime.block_on(self.client.put_object().bucket(&self.s3.bucket).key(key).body(body).send())
+            .context(format!("writing {}", self.url(key)))?;
+        Ok(())
+    }
+
+    /// Removes the objects at `relatives` in snapshot `name`.
+    fn delete_objects(&self, name: &str, relatives: &[PathBuf]) -> Result<()> {
+        let keys = relatives.iter().map(|relative| self.object_key(name, relative)).collect::<Result<Vec<_>>>()?;
+        self.runtime.block_on(async {
+            for batch in keys.chunks(DELETE_BATCH) {
+                let objects = batch.iter()
+                    .map(|key| ObjectIdentifier::builder().key(key).build())
+                    .collect::<Result<Vec<_>, _>>()?;
+                let delete = Delete::builder().set_objects(Some(objects)).quiet(true).build()?;
+                let output = self.client.delete_objects().bucket(&self.s3.bucket).delete(delete).send().await
+                    .context(format!("removing objects of {name}"))?;
+                if let Some(error) = output.errors().first() {
+                    return Err(anyhow!("removing {} failed: {}", self.url(error.key().unwrap_or_default()), error.message().unwrap_or_default()));
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// True if the file with `size` and `modified` differs from the object `remote`, or there is none.
+fn is_outdated(remote: Option<&(u64, SystemTime)>, size: u64, modified: SystemTime) -> bool {
+    match remote {
+        Some((remote_size, written)) => *remote_size != size || modified > *written,
+        None => true
+    }
+}
+
+/// `key` as the `x-amz-copy-source` header needs it: everything but unreserved characters and `/` is
+/// percent-encoded.
+fn encode_copy_source(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+impl Backend for S3Backend<'_> {
+    fn latest_snapshot(&self) -> Result<Option<(DateTime<FixedOffset>, String)>> {
+        Ok(self.list()?.into_iter().max())
+    }
+
+    fn snapshot_count(&self) -> Result<usize> {
+        Ok(self.list()?.len())
+    }
+
+    fn has_snapshot(&self, name: &str) -> Result<bool> {
+        Ok(self.list()?.iter().any(|(_, listed)| listed == name))
+    }
+
+    fn create_empty_snapshot(&self, name: &str) -> Result<()> {
+        self.put_object(&format!("{}/{SNAPSHOT_MARKER}", self.key(name)), ByteStream::from_static(b""))
+    }
+
+    fn extract_changes(&self, latest: &str, _new: &str) -> Result<Option<ChangeList>> {
+        self.pending.replace(None);
+        let excludes = ExcludeMatcher::load(&self.config.exclude)?;
+        let local = self.list_working_dir(&excludes)?;
+        let remote = self.list_objects(latest)?;
+        let upload: Vec<PathBuf> = local.iter()
+            .filter(|(relative, (size, modified))| is_outdated(remote.get(*relative), *size, *modified))
+            .map(|(relative, _)| relative.clone())
+            .collect();
+        // like rsync, excluded files are neither copied nor deleted
+        let delete: Vec<PathBuf> = remote.keys()
+            .filter(|relative| relative.as_os_str() != SNAPSHOT_MARKER && !local.contains_key(*relative) && !excludes.is_excluded(relative))
+            .cloned()
+            .collect();
+        debug!("{} files to upload, {} objects to remove", upload.len(), delete.len());
+        if upload.is_empty() && delete.is_empty() {
+            return Ok(None);
+        }
+        let changes = ChangeList {
+            deleted: delete.iter().cloned().map(FsEntity::File).collect(),
+            changed: upload.iter().cloned().map(FsEntity::File).collect(),
+            created: vec![],
+            moved: vec![],
+        };
+        self.pending.replace(Some(PendingChanges { upload, delete }));
+        Ok(Some(changes))
+    }
+
+    fn copy_snapshot(&self, latest: &str, new: &str, fast_forward: bool) -> Result<()> {
+        let objects: Vec<PathBuf> = self.list_objects(latest)?.into_keys().collect();
+        info!("copying {} objects of {latest} to {new}", objects.len());
+        for relative in &objects {
+            let from = self.object_key(latest, relative)?;
+            let to = self.object_key(new, relative)?;
+            let copy_source = format!("{}/{}", self.s3.bucket, encode_copy_source(&from));
+            self.runtime.block_on(self.client.copy_object().bucket(&self.s3.bucket).copy_source(copy_source).key(&to).send())
+                .context(format!("copying {} to {}", self.url(&from), self.url(&to)))?;
+        }
+        if fast_forward {
+            info!("removing {latest}, it is fast-forwarded to {new}");
+            self.delete_objects(latest, &objects)?;
+        }
+        Ok(())
+    }
+
+    fn apply_changes(&self, _latest: &str, new: &str, _fast_forward: bool) -> Result<()> {
+        let PendingChanges { upload, delete } = self.pending.take()
+            .ok_or(anyhow!("there are no extracted changes to apply to {new}"))?;
+        info!("uploading {} files into {new}, removing {} objects", upload.len(), delete.len());
+        let source = self.config.source_dir();
+        for relative in &upload {
+            let path = source.join(relative);
+            if !path.exists() {
+                if self.config.treat_vanished_as_error {
+                    return Err(anyhow!("{path:?} vanished before it was uploaded"));
+                }
+                warn!("{path:?} vanished before it was uploaded");
+                continue;
+            }
+            let body = self.runtime.block_on(ByteStream::from_path(&path)).context(format!("reading {path:?}"))?;
+            self.put_object(&self.object_key(new, relative)?, body)?;
+        }
+        self.delete_objects(new, &delete)
+    }
+
+    fn file_count(&self, name: &str) -> Result<Option<usize>> {
+        let objects = self.list_objects(name)?;
+        Ok(Some(objects.keys().filter(|relative| relative.as_os_str() != SNAPSHOT_MARKER).count()))
+    }
+
+    fn discard_changes(&self, _new: &str) -> Result<()> {
+        self.pending.replace(None);
+        Ok(())
+    }
+
+    fn has_sidecar(&self, name: &str, ext: &str) -> Result<bool> {
+        let key = self.key(&format!("{name}.{ext}"));
+        match self.runtime.block_on(self.client.head_object().bucket(&self.s3.bucket).key(&key).send()) {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+            Err(e) => Err(e).context(format!("checking for {}", self.url(&key)))
+        }
+    }
+
+    fn write_sidecar(&self, name: &str, ext: &str, contents: &[u8]) -> Result<()> {
+        self.put_object(&self.key(&format!("{name}.{ext}")), ByteStream::from(contents.to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn outdated_objects() {
+        let written = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        assert!(is_outdated(None, 10, written));
+        assert!(is_outdated(Some(&(10, written)), 11, written));
+        assert!(is_outdated(Some(&(10, written)), 10, written + Duration::from_secs(1)));
+        assert!(!is_outdated(Some(&(10, written)), 10, written));
+        assert!(!is_outdated(Some(&(10, written)), 10, written - Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn copy_source_is_percent_encoded() {
+        assert_eq!(encode_copy_source("backups/Oct16_2026_120000+0000/a b/ü.txt"), "backups/Oct16_2026_120000%2B0000/a%20b/%C3%BC.txt");
+    }
+}
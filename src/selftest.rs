@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use tempfile::tempdir;
+use tracing::info;
+use crate::archive::{archive_local, diff_against_latest};
+use crate::config::Config;
+use crate::restore::restore_snapshot;
+use crate::syncer_util::timestamp_named_folders;
+use crate::util::walk_files;
+
+/// Relative path -> contents of every file below `dir`.
+fn read_tree(dir: &Path) -> Result<BTreeMap<PathBuf, Vec<u8>>> {
+    let mut tree = BTreeMap::new();
+    for file in walk_files(dir).context(format!("walking {dir:?}"))? {
+        let contents = fs::read(&file).context(format!("reading {file:?}"))?;
+        tree.insert(file.strip_prefix(dir)?.to_path_buf(), contents);
+    }
+    Ok(tree)
+}
+
+/// Describes the differences between two trees, empty if they are equal.
+fn compare_trees(expected: &BTreeMap<PathBuf, Vec<u8>>, actual: &BTreeMap<PathBuf, Vec<u8>>) -> Vec<String> {
+    let mut mismatches = Vec::new();
+    for (path, contents) in expected {
+        match actual.get(path) {
+            None => mismatches.push(format!("missing {path:?}")),
+            Some(actual_contents) if actual_contents != contents => mismatches.push(format!("different contents of {path:?}")),
+            Some(_) => {}
+        }
+    }
+    for path in actual.keys().filter(|path| !expected.contains_key(*path)) {
+        mismatches.push(format!("unexpected {path:?}"));
+    }
+    mismatches
+}
+
+fn check_tree(expected: &BTreeMap<PathBuf, Vec<u8>>, dir: &Path) -> Result<()> {
+    let mismatches = compare_trees(expected, &read_tree(dir)?);
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    for mismatch in &mismatches {
+        println!("    {mismatch}");
+    }
+    Err(anyhow!("{dir:?} doesn't match the expected files"))
+}
+
+fn write_file(root: &Path, relative: &str, contents: &str) -> Result<()> {
+    let path = root.join(relative);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, contents).context(format!("writing {path:?}"))
+}
+
+/// Archives a sample tree twice in a temp dir, restores the first snapshot and compares it with the original.
+pub fn selftest() -> Result<()> {
+    let temp_dir = tempdir()?;
+    let root = temp_dir.path();
+    let working_dir = root.join("work");
+    let local_archive = root.join("archive");
+    let exclude = root.join("exclude.txt");
+    fs::create_dir_all(&working_dir)?;
+    fs::create_dir_all(&local_archive)?;
+    fs::write(&exclude, "")?;
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "local_working_dir": working_dir,
+        "local_archive": local_archive,
+        "exclude": exclude,
+        // both runs happen on the same day, the first snapshot must survive the second one
+        "fast_forward": false,
+    }))?;
+
+    write_file(&working_dir, "a.txt", "first version")?;
+    write_file(&working_dir, "dir/b.txt", "to be moved")?;
+    write_file(&working_dir, "dir/nested/c.txt", "unchanged")?;
+    let first_state = read_tree(&working_dir)?;
+    info!("selftest: first archive run");
+    archive_local(&config).context("first archive run")?;
+
+    write_file(&working_dir, "a.txt", "second version")?;
+    fs::create_dir_all(working_dir.join("other"))?;
+    fs::rename(working_dir.join("dir/b.txt"), working_dir.join("other/b.txt"))?;
+    write_file(&working_dir, "new.txt", "added")?;
+    let second_state = read_tree(&working_dir)?;
+
+    let changes = diff_against_latest(&config)?.ok_or(anyhow!("changes in the working dir were not detected"))?;
+    if !changes.moved.iter().any(|(from, to)| from.path() == Path::new("dir/b.txt") && to == Path::new("other/b.txt")) {
+        return Err(anyhow!("move of dir/b.txt to other/b.txt was not detected: {changes:?}"));
+    }
+
+    // snapshot names have a resolution of one second
+    sleep(Duration::from_millis(1100));
+    info!("selftest: second archive run");
+    archive_local(&config).context("second archive run")?;
+
+    // the initial empty snapshot and one per run
+    let snapshots = timestamp_named_folders(&local_archive, &config.naming())?;
+    if snapshots.len() != 3 {
+        return Err(anyhow!("expected 3 snapshots, found {:?}", snapshots.iter().map(|(_, name)| name).collect::<Vec<_>>()));
+    }
+    check_tree(&second_state, &local_archive.join(&snapshots[2].1)).context("latest snapshot")?;
+
+    let restored = root.join("restored");
+    restore_snapshot(&config, &snapshots[1].1, &restored, None).context("restoring the first snapshot")?;
+    check_tree(&first_state, &restored).context("restored first snapshot")?;
+    println!("selftest passed");
+    Ok(())
+}
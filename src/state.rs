@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+/// Written next to the lock file, maps each working dir archived into the folder to its `RunState`, as targets may
+/// share an archive.
+const STATE_FILE: &str = ".vhbarchsync.state.json";
+
+/// What the last successful archive run of a working dir saw, see `Config::since_last_success`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunState {
+    /// RFC 3339
+    pub last_success: String,
+    /// Latest modification time in the working dir, taken before rsync ran
+    pub source_max_mtime: SystemTime,
+}
+
+fn read_states(local_archive: &Path) -> BTreeMap<PathBuf, RunState> {
+    fs::read_to_string(local_archive.join(STATE_FILE)).ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// State of the last successful run of `working_dir` into `local_archive`, None if there is none.
+pub fn read_state(local_archive: &Path, working_dir: &Path) -> Option<RunState> {
+    read_states(local_archive).remove(working_dir)
+}
+
+/// Records a successful run of `working_dir` that saw `source_max_mtime`. The file is replaced by a rename, so it
+/// is never half written.
+pub fn write_state(local_archive: &Path, working_dir: &Path, source_max_mtime: SystemTime) -> Result<()> {
+    let mut states = read_states(local_archive);
+    states.insert(working_dir.to_path_buf(), RunState { last_success: Local::now().to_rfc3339(), source_max_mtime });
+    let path = local_archive.join(STATE_FILE);
+    let temp = local_archive.join(format!("{STATE_FILE}.tmp"));
+    fs::write(&temp, serde_json::to_string_pretty(&states).context("serializing run state")?).context(format!("writing {temp:?}"))?;
+    fs::rename(&temp, &path).context(format!("renaming {temp:?} to {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn states_round_trip_per_working_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (Path::new("/work/a"), Path::new("/work/b"));
+        assert!(read_state(dir.path(), a).is_none());
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789);
+        write_state(dir.path(), a, mtime).unwrap();
+        write_state(dir.path(), b, SystemTime::UNIX_EPOCH).unwrap();
+        assert_eq!(read_state(dir.path(), a).unwrap().source_max_mtime, mtime);
+
+        let later = mtime + Duration::from_secs(60);
+        write_state(dir.path(), a, later).unwrap();
+        assert_eq!(read_state(dir.path(), a).unwrap().source_max_mtime, later);
+        assert_eq!(read_state(dir.path(), b).unwrap().source_max_mtime, SystemTime::UNIX_EPOCH);
+        assert!(!dir.path().join(format!("{STATE_FILE}.tmp")).exists());
+    }
+
+    #[test]
+    fn unreadable_state_is_no_state() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(STATE_FILE), "{").unwrap();
+        assert!(read_state(dir.path(), Path::new("/work")).is_none());
+        // the next successful run replaces it
+        write_state(dir.path(), Path::new("/work"), SystemTime::UNIX_EPOCH).unwrap();
+        assert!(read_state(dir.path(), Path::new("/work")).is_some());
+    }
+}
@@ -0,0 +1,43 @@
+//! Helpers shared by the unit tests.
+use std::fs;
+use std::path::Path;
+use chrono::{Duration, Local};
+use crate::config::Config;
+use crate::syncer_util::SnapshotNaming;
+use crate::util::find_executable_in_path;
+
+/// Config archiving `root/work` into `root/archive` with an empty exclude file, all three are created.
+pub fn test_config(root: &Path) -> Config {
+    fs::create_dir_all(root.join("work")).unwrap();
+    fs::create_dir_all(root.join("archive")).unwrap();
+    fs::write(root.join("exclude.txt"), "").unwrap();
+    serde_json::from_value(serde_json::json!({
+        "local_working_dir": root.join("work"),
+        "local_archive": root.join("archive"),
+        "exclude": root.join("exclude.txt"),
+    })).unwrap()
+}
+
+/// Naming of `test_config`, the default one.
+pub fn naming() -> SnapshotNaming {
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "local_working_dir": "/work",
+        "local_archive": "/archive",
+        "exclude": "/exclude.txt",
+    })).unwrap();
+    config.naming()
+}
+
+/// Name of a snapshot taken `days` days ago.
+pub fn snapshot_name(naming: &SnapshotNaming, days: i64) -> String {
+    naming.format(&(Local::now() - Duration::days(days)))
+}
+
+/// Tests running rsync pass without doing anything where it isn't installed.
+pub fn rsync_available() -> bool {
+    let available = find_executable_in_path("rsync").is_some();
+    if !available {
+        eprintln!("rsync is not installed, skipping");
+    }
+    available
+}
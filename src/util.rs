@@ -1,14 +1,26 @@
-use std::{env, io};
+use std::{env, fs, io};
+use std::io::{BufRead, IsTerminal, Write};
 use std::ffi::OsString;
 use std::fmt::Debug;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use path_clean::PathClean;
 use anyhow::{anyhow, Context, Result};
-use pathsearch::find_executable_in_path;
 use subprocess::{Exec, Redirection};
-use tracing::{debug, instrument, trace};
+use tempfile::TempDir;
+use tracing::{debug, info, instrument, trace, warn};
+
+/// First executable file named `name` in the folders of `PATH`.
+pub fn find_executable_in_path(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0))
+}
 
 #[allow(dead_code)]
 pub fn absolute_path(path: impl AsRef<Path>) -> io::Result<PathBuf> {
@@ -23,6 +35,26 @@ pub fn absolute_path(path: impl AsRef<Path>) -> io::Result<PathBuf> {
     Ok(absolute_path)
 }
 
+/// Source of filesystem device ids, so that cross-device checks don't depend on real mounts.
+pub trait DeviceIds {
+    fn device_id(&self, path: &Path) -> io::Result<u64>;
+}
+
+/// Device ids from `stat`.
+pub struct FsDeviceIds;
+
+impl DeviceIds for FsDeviceIds {
+    fn device_id(&self, path: &Path) -> io::Result<u64> {
+        use std::os::unix::fs::MetadataExt;
+        Ok(fs::metadata(path)?.dev())
+    }
+}
+
+/// True if `mv a b_dir/..` would be a rename and not a copy and delete.
+pub fn same_device(ids: &dyn DeviceIds, a: &Path, b: &Path) -> io::Result<bool> {
+    Ok(ids.device_id(a)? == ids.device_id(b)?)
+}
+
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub enum CpMvMode {
     File,
@@ -88,11 +120,19 @@ pub fn fs_move(src_path: &Path, dst_folder: &Path, mode: CpMvMode) -> Result<()>
     Ok(())
 }
 
+/// Makes ssh started by `exec` ask `askpass` for passwords instead of the terminal.
+pub fn with_ssh_askpass(exec: Exec, askpass: Option<&Path>) -> Exec {
+    match askpass {
+        Some(askpass) => exec.env("SSH_ASKPASS", askpass).env("SSH_ASKPASS_REQUIRE", "force"),
+        None => exec
+    }
+}
+
 #[instrument]
-pub fn ssh_execute_remote<S: AsRef<str> + Debug>(user: S, host: S, port: u16, command: S) -> Result<String> {
+pub fn ssh_execute_remote<S: AsRef<str> + Debug>(user: S, host: S, port: u16, command: S, askpass: Option<&Path>) -> Result<String> {
     trace!("executing");
     let ssh_path = find_executable_in_path("ssh").context("failed to find ssh in PATH")?;
-    let ssh_exec = Exec::cmd(ssh_path)
+    let ssh_exec = with_ssh_askpass(Exec::cmd(ssh_path), askpass)
         .arg("-p")
         .arg(format!("{}", port))
         .arg(format!("{}@{}", user.as_ref(), host.as_ref()))
@@ -139,22 +179,12 @@ pub fn add_trailing_slash(mut p: PathBuf) -> PathBuf {
 
 // see https://www.reddit.com/r/rust/comments/ooh5wn/damn_trailing_slash/ for more fun
 pub fn remove_trailing_slash(p: &mut PathBuf) {
-    if has_trailing_slash(&p) {
-        let dirname = if let Some(fname) = p.file_name() {
-            match fname.to_str() {
-                Some(fname) => {
-                    let mut s = OsString::with_capacity(fname.len() - 1);
-                    s.push(OsString::from_str(&fname[..fname.len() - 2]).expect(""));
-                    s
-                }
-                None => OsString::new()
+    if has_trailing_slash(p) {
+        // file_name() already leaves the trailing slash out
+        if let Some(fname) = p.file_name().map(|fname| fname.to_owned()) {
+            if p.pop() {
+                p.push(fname);
             }
-        } else {
-            OsString::new()
-        };
-
-        if p.pop() {
-            p.push(dirname);
         }
     }
 }
@@ -163,19 +193,394 @@ pub fn path_to_str(p: &Path) -> Result<&str> {
     p.to_str().ok_or(anyhow!("Path::to_str() failed, non-unicode symbols in path?"))
 }
 
-pub fn enclose_path_in(p: &Path, symbol: char) -> Result<String> {
-    let p = path_to_str(p)?;
-    let mut s = String::with_capacity(p.len() + 2);
-    s.push(symbol);
-    s.push_str(p);
-    s.push(symbol);
-    Ok(s)
-}
-
+#[allow(dead_code)]
 pub fn concat_str_path<S: AsRef<str>>(s: S, p: &Path) -> Result<String> {
     let p = path_to_str(p)?;
     let mut c = String::with_capacity(s.as_ref().len() + p.len());
     c.push_str(s.as_ref());
     c.push_str(p);
     Ok(c)
-}
\ No newline at end of file
+}
+
+/// Like `concat_str_path`, but works with non-unicode paths, use it for building arguments.
+pub fn concat_str_os<S: AsRef<str>>(s: S, p: &Path) -> OsString {
+    let mut c = OsString::with_capacity(s.as_ref().len() + p.as_os_str().len());
+    c.push(s.as_ref());
+    c.push(p.as_os_str());
+    c
+}
+/// Latest modification time of `dir` and everything below it, symlinks are not followed.
+pub fn max_mtime(dir: &Path) -> io::Result<SystemTime> {
+    let mut latest = fs::symlink_metadata(dir)?.modified()?;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let mtime = if metadata.is_dir() {
+            max_mtime(&entry.path())?
+        } else {
+            metadata.modified()?
+        };
+        latest = latest.max(mtime);
+    }
+    Ok(latest)
+}
+
+/// Parses durations like `90s`, `10m`, `1h` or `7d`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number.parse().context(format!("invalid duration {s:?}"))?;
+    let multiplier = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(anyhow!("invalid duration {s:?}, expected a number followed by s, m, h or d"))
+    };
+    if number == 0 {
+        return Err(anyhow!("duration must be positive: {s:?}"));
+    }
+    Ok(Duration::from_secs(number * multiplier))
+}
+
+/// Parses sizes like `4096`, `100K`, `500M` or `2G` (binary units, an optional trailing `B` is allowed) into bytes.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let trimmed = s.trim();
+    let split = trimmed.find(|c: char| !c.is_ascii_digit()).unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let number: u64 = number.parse().context(format!("invalid size {s:?}"))?;
+    let unit = unit.trim().to_ascii_uppercase();
+    let shift = match unit.strip_suffix('B').unwrap_or(&unit) {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(anyhow!("invalid size {s:?}, expected a number optionally followed by K, M, G or T"))
+    };
+    if number == 0 {
+        return Err(anyhow!("size must be positive: {s:?}"));
+    }
+    number.checked_mul(1 << shift).ok_or(anyhow!("size is too large: {s:?}"))
+}
+
+/// Total size of the regular files below `dir`.
+pub fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for file in walk_files(dir)? {
+        size += fs::symlink_metadata(file)?.len();
+    }
+    Ok(size)
+}
+
+/// Fails if `available` bytes can't hold `needed` bytes plus `margin`.
+pub fn check_free_space(path: &Path, available: u64, needed: u64, margin: u64) -> Result<()> {
+    let required = needed.saturating_add(margin);
+    if available < required {
+        return Err(anyhow!("not enough free space in {path:?}: {available} bytes available, {needed} bytes needed plus a margin of {margin} bytes"));
+    }
+    Ok(())
+}
+
+pub fn hash_file(path: &Path) -> Result<blake3::Hash> {
+    let mut file = fs::File::open(path).context(format!("opening {path:?}"))?;
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher).context(format!("reading {path:?}"))?;
+    Ok(hasher.finalize())
+}
+
+/// All regular files below `dir`, symlinks and special files are skipped.
+pub fn walk_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            files.extend(walk_files(&entry.path())?);
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// Returns true if `dir` doesn't exist or has no entries.
+pub fn is_empty_dir(dir: &Path) -> io::Result<bool> {
+    match fs::read_dir(dir) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e)
+    }
+}
+
+fn is_disallowed_in_filename(c: char) -> bool {
+    matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_whitespace() || c.is_control()
+}
+
+/// Maps characters that are not allowed in file names on common filesystems (and whitespace) to `-`.
+pub fn sanitize_timestamp_for_filename(s: &str) -> String {
+    s.chars().map(|c| if is_disallowed_in_filename(c) { '-' } else { c }).collect()
+}
+
+/// Rewrites a chrono format string so that its output is a valid file name and still parses with the
+/// rewritten format: literal characters are sanitized, composite specifiers are expanded with `-` separators
+/// and space padded specifiers are zero padded instead.
+pub fn sanitize_date_format_for_filename(format: &str) -> String {
+    let mut sanitized = String::with_capacity(format.len());
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            sanitized.push(if is_disallowed_in_filename(c) { '-' } else { c });
+            continue;
+        }
+        let mut modifiers = String::new();
+        while let Some(&m) = chars.peek() {
+            if m == '-' || m == '_' || m == '0' || m == '.' || m == ':' || m == '#' || m.is_ascii_digit() {
+                modifiers.push(m);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        let spec = match chars.next() {
+            Some(spec) => spec,
+            None => {
+                sanitized.push('%');
+                sanitized.push_str(&modifiers);
+                break;
+            }
+        };
+        let modifiers = modifiers.replace('_', "0");
+        match (modifiers.as_str(), spec) {
+            ("", 'T') => sanitized.push_str("%H-%M-%S"),
+            ("", 'R') => sanitized.push_str("%H-%M"),
+            ("", 'D') => sanitized.push_str("%m-%d-%y"),
+            ("", 'r') => sanitized.push_str("%I-%M-%S-%p"),
+            ("", 'e') => sanitized.push_str("%d"),
+            ("", 'k') => sanitized.push_str("%H"),
+            ("", 'l') => sanitized.push_str("%I"),
+            (m, 'z') if m.contains(':') => sanitized.push_str("%z"),
+            (m, spec) => {
+                sanitized.push('%');
+                sanitized.push_str(m);
+                sanitized.push(spec);
+            }
+        }
+    }
+    sanitized
+}
+
+/// New temp dir in the system temp dir (`TMPDIR`), or in `fallback` if that fails. The error names the folders tried.
+pub fn create_temp_dir(fallback: Option<&Path>) -> Result<TempDir> {
+    create_temp_dir_in(&env::temp_dir(), fallback)
+}
+
+fn create_temp_dir_in(system: &Path, fallback: Option<&Path>) -> Result<TempDir> {
+    match (tempfile::tempdir_in(system), fallback) {
+        (Ok(dir), _) => Ok(dir),
+        (Err(e), Some(fallback)) => {
+            warn!("unable to create a temp dir in {system:?}, using {fallback:?}: {e}");
+            tempfile::tempdir_in(fallback).context(format!("unable to create a temp dir in {system:?} ({e}) or in temp_dir {fallback:?}"))
+        }
+        (Err(e), None) => Err(e).context(format!("unable to create a temp dir in {system:?}, set TMPDIR, temp_dir or --tmpdir to a writable folder"))
+    }
+}
+
+/// Temp dir that is only created once a path in it is needed, see `create_temp_dir`.
+pub struct LazyTempDir {
+    fallback: Option<PathBuf>,
+    dir: OnceLock<TempDir>,
+}
+
+impl LazyTempDir {
+    pub fn new(fallback: Option<PathBuf>) -> Self {
+        LazyTempDir { fallback, dir: OnceLock::new() }
+    }
+
+    pub fn path(&self) -> Result<&Path> {
+        if let Some(dir) = self.dir.get() {
+            return Ok(dir.path());
+        }
+        let dir = create_temp_dir(self.fallback.as_deref())?;
+        Ok(self.dir.get_or_init(|| dir).path())
+    }
+}
+
+/// Paths a batch operation failed on with `--keep-going`, and why.
+pub type Failures = Vec<(PathBuf, anyhow::Error)>;
+
+/// Runs `f` on each path. Stops at the first error, unless `keep_going` is set, then the errors are collected.
+pub fn for_each_path(paths: &[PathBuf], keep_going: bool, mut f: impl FnMut(&Path) -> Result<()>) -> Result<Failures> {
+    let mut failures = Vec::new();
+    for path in paths {
+        if let Err(e) = f(path) {
+            if !keep_going {
+                return Err(e);
+            }
+            warn!("{path:?}: {e:#}");
+            failures.push((path.clone(), e));
+        }
+    }
+    Ok(failures)
+}
+
+/// Asks a yes/no question when stdin is a terminal. `assume_yes` skips the question,
+/// without a terminal `non_interactive_answer` is returned.
+pub fn confirm(prompt: &str, assume_yes: bool, non_interactive_answer: bool) -> bool {
+    let is_terminal = io::stdin().is_terminal();
+    ask(prompt, assume_yes, non_interactive_answer, is_terminal, &mut io::stdin().lock(), &mut io::stdout())
+}
+
+/// `confirm` reading the answer from `input` and writing the question to `output`.
+fn ask(prompt: &str, assume_yes: bool, non_interactive_answer: bool, is_terminal: bool, input: &mut impl BufRead, output: &mut impl Write) -> bool {
+    if assume_yes {
+        return true;
+    }
+    if !is_terminal {
+        return non_interactive_answer;
+    }
+    if write!(output, "{prompt} [y/N] ").and_then(|_| output.flush()).is_err() {
+        return false;
+    }
+    let mut answer = String::new();
+    if input.read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Runs `f`, logging `<what> in progress` with the elapsed time every `interval` until it returns. The heartbeat
+/// thread is stopped and joined before this returns, also when `f` fails or panics.
+pub fn with_heartbeat<T>(what: &str, interval: Option<Duration>, f: impl FnOnce() -> T) -> T {
+    match interval {
+        Some(interval) => beating(interval, |elapsed| info!("{what} in progress: {}s", elapsed.as_secs()), f),
+        None => f()
+    }
+}
+
+/// Runs `f` while a scoped thread calls `beat` with the elapsed time every `interval`.
+fn beating<T>(interval: Duration, beat: impl Fn(Duration) + Send, f: impl FnOnce() -> T) -> T {
+    let (done, stopped) = mpsc::channel::<()>();
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            let started = Instant::now();
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                beat(started.elapsed());
+            }
+        });
+        let result = f();
+        drop(done);
+        result
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn colons_slashes_and_spaces_are_sanitized() {
+        assert_eq!(sanitize_timestamp_for_filename("May 01 2024 10:00:00 +02:00"), "May-01-2024-10-00-00-+02-00");
+        assert_eq!(sanitize_timestamp_for_filename("2024/05/01"), "2024-05-01");
+        assert_eq!(sanitize_timestamp_for_filename("May01_2024_100000+0200"), "May01_2024_100000+0200");
+    }
+
+    #[test]
+    fn sanitized_formats_round_trip() {
+        assert_eq!(sanitize_date_format_for_filename("%Y-%m-%d %H:%M:%S %:z"), "%Y-%m-%d-%H-%M-%S-%z");
+        assert_eq!(sanitize_date_format_for_filename("%F %T%z"), "%F-%H-%M-%S%z");
+        assert_eq!(sanitize_date_format_for_filename("%b%e_%Y_%H%M%S%z"), "%b%d_%Y_%H%M%S%z");
+        let datetime = chrono::DateTime::parse_from_rfc3339("2024-05-01T09:05:07+02:00").unwrap();
+        for format in ["%Y-%m-%d %H:%M:%S %:z", "%a %b %e %T %Y %z", "%D %r %z", "%b%d_%Y_%H%M%S%z"] {
+            let sanitized = sanitize_date_format_for_filename(format);
+            let name = datetime.format(&sanitized).to_string();
+            assert!(!name.contains([':', ' ', '/']), "{format}: {name}");
+            assert_eq!(sanitize_timestamp_for_filename(&name), name);
+            assert_eq!(chrono::DateTime::parse_from_str(&name, &sanitized).unwrap(), datetime, "{format}: {name}");
+        }
+    }
+
+    #[test]
+    fn slow_operation_gets_heartbeats() {
+        let beats = AtomicUsize::new(0);
+        let result = beating(Duration::from_millis(10), |_| { beats.fetch_add(1, Ordering::SeqCst); }, || {
+            thread::sleep(Duration::from_millis(100));
+            42
+        });
+        assert_eq!(result, 42);
+        assert!(beats.load(Ordering::SeqCst) >= 1);
+    }
+
+    #[test]
+    fn heartbeat_thread_is_joined_on_error() {
+        let beats = Arc::new(AtomicUsize::new(0));
+        let counter = beats.clone();
+        let result: Result<()> = beating(Duration::from_millis(10), move |_| { counter.fetch_add(1, Ordering::SeqCst); }, || {
+            thread::sleep(Duration::from_millis(50));
+            Err(anyhow!("failed"))
+        });
+        assert!(result.is_err());
+        // the thread owned the other reference, it is gone once the thread has finished
+        assert_eq!(Arc::strong_count(&beats), 1);
+        let count = beats.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(beats.load(Ordering::SeqCst), count);
+    }
+
+    #[test]
+    fn unwritable_temp_dir_is_named() {
+        let error = create_temp_dir_in(Path::new("/nonexistent/tmp"), None).unwrap_err();
+        assert!(format!("{error:#}").contains("\"/nonexistent/tmp\""), "{error:#}");
+    }
+
+    #[test]
+    fn temp_dir_falls_back() {
+        let fallback = tempfile::tempdir().unwrap();
+        let dir = create_temp_dir_in(Path::new("/nonexistent/tmp"), Some(fallback.path())).unwrap();
+        assert_eq!(dir.path().parent(), Some(fallback.path()));
+
+        let error = create_temp_dir_in(Path::new("/nonexistent/tmp"), Some(Path::new("/nonexistent/fallback"))).unwrap_err();
+        let message = format!("{error:#}");
+        assert!(message.contains("\"/nonexistent/tmp\"") && message.contains("\"/nonexistent/fallback\""), "{message}");
+    }
+
+    /// Answer of `ask` given `input`, and whether the question was shown.
+    fn answer(assume_yes: bool, non_interactive_answer: bool, is_terminal: bool, input: &str) -> (bool, bool) {
+        let mut output = Vec::new();
+        let answer = ask("Remove?", assume_yes, non_interactive_answer, is_terminal, &mut input.as_bytes(), &mut output);
+        (answer, !output.is_empty())
+    }
+
+    #[test]
+    fn assume_yes_skips_the_question() {
+        assert_eq!(answer(true, false, true, "n\n"), (true, false));
+        assert_eq!(answer(true, false, false, ""), (true, false));
+    }
+
+    #[test]
+    fn non_interactive_uses_the_configured_answer() {
+        assert_eq!(answer(false, true, false, "n\n"), (true, false));
+        assert_eq!(answer(false, false, false, "y\n"), (false, false));
+    }
+
+    #[test]
+    fn terminal_answers() {
+        assert_eq!(answer(false, true, true, "y\n"), (true, true));
+        assert_eq!(answer(false, true, true, " YES \n"), (true, true));
+        assert_eq!(answer(false, true, true, "n\n"), (false, true));
+        assert_eq!(answer(false, true, true, "\n"), (false, true));
+        assert_eq!(answer(false, true, true, ""), (false, true));
+    }
+
+    #[test]
+    fn trailing_slash_is_removed() {
+        for (path, expected) in [("/data/dir/", "/data/dir"), ("/data/dir", "/data/dir"), ("dir/", "dir"), ("/", "/"), ("/data/a/", "/data/a")] {
+            let mut p = PathBuf::from(path);
+            remove_trailing_slash(&mut p);
+            assert_eq!(p, PathBuf::from(expected), "{path}");
+        }
+    }
+}